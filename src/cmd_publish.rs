@@ -1,34 +1,48 @@
-use std::{
-    collections::HashMap,
-    fs,
-    io::{Read, Write},
-    path::PathBuf,
-};
+use std::{fs, path::PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Args;
 use elementtree::Element;
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use log::info;
+use log::{info, warn};
 use ostree::{
     gio::{Cancellable, File},
-    glib::VariantDict,
-    prelude::Cast,
-    MutableTree, Repo,
+    glib::{DateTime, KeyFile, KeyFileFlags, Variant, VariantDict},
+    prelude::{Cast, FileExt, SignExt},
+    MutableTree, Repo, Sign,
 };
+use roxmltree::{Document, Node, ParsingOptions};
+use serde::Serialize;
 
 use crate::{
-    config::{Config, RegularConfig, ValidateConfig},
-    job_utils::BuildExtended,
+    config::{Branding, Config, RegularConfig, SubsetRule, UploadSignatureConfig, ValidateConfig},
+    job_utils::{Build, BuildExtended, BuildRef},
+    journal::{append_journal, JournalBatch, JournalRecord},
     storefront::StorefrontInfo,
-    utils::{app_id_from_ref, mtree_lookup, mtree_lookup_file, read_file_from_repo, Transaction},
+    utils::{
+        app_id_from_ref, appstream_catalog_candidates, glob_match, hooks_version, is_primary_ref,
+        load_appstream, mtree_lookup, mtree_lookup_file, read_file_from_repo, Transaction,
+    },
+    xml_edit::{self, XmlEditor},
 };
 
+/// Commit metadata keys for the build provenance. They're prefixed with the branding namespace, see `provenance_key`.
+const PROVENANCE_BUILD_ID: &str = "build.id";
+const PROVENANCE_BUILD_LOG_URL: &str = "build.log-url";
+const PROVENANCE_BUILD_REF_LOG_URL: &str = "build.ref-log-url";
+const PROVENANCE_HOOKS_VERSION: &str = "build.hooks-version";
+
+/// Detached metadata key of a commit's GPG signatures.
+const GPG_SIGNATURES_KEY: &str = "ostree.gpgsigs";
+
 #[derive(Args, Debug)]
 pub struct PublishArgs {
     /// Path to the config file. The script is usually run in the build directory, so this needs to be an absolute path.
     #[arg(short, long)]
     config: PathBuf,
+
+    /// Print a JSON report of the changes that would be made to each ref, without writing anything to the repo.
+    #[arg(long)]
+    dry_run: bool,
 }
 
 impl PublishArgs {
@@ -42,13 +56,38 @@ impl PublishArgs {
         let refs = repo.list_refs(None, Cancellable::NONE)?;
 
         // Get build info from flat-manager
-        let build = if config.get_is_republish()? {
-            None
+        let (build_id, build) = if config.get_is_republish()? {
+            (None, None)
         } else {
-            Some(config.get_build()?)
+            (Some(config.get_build_id()?), Some(config.get_build()?))
+        };
+
+        // Fetch the storefront info for every app in the build up front, so each app is only requested once
+        let mut app_ids: Vec<String> = refs.keys().map(|r| app_id_from_ref(r)).collect();
+        app_ids.sort();
+        app_ids.dedup();
+        let storefront_infos = config.get_storefront_infos(&app_ids)?;
+        for (app_id, storefront_info) in &storefront_infos {
+            storefront_info
+                .check_consistency()
+                .with_context(|| format!("Invalid storefront info for {app_id}"))?;
+        }
+
+        let mut dry_run_reports = vec![];
+        let mut journal_records = vec![];
+        let batch = JournalBatch {
+            build_id,
+            timestamp: DateTime::now_utc()?
+                .format("%Y-%m-%dT%H:%M:%SZ")?
+                .to_string(),
         };
 
-        let mut storefront_infos = HashMap::new();
+        // Stage all the rewrites in a single transaction, so the refs are only updated if every one of them succeeds
+        let tx = if self.dry_run {
+            None
+        } else {
+            Some(Transaction::new(&repo)?)
+        };
 
         // Rewrite each one
         for (refstring, checksum) in refs.into_iter() {
@@ -58,41 +97,327 @@ impl PublishArgs {
 
             let app_id = app_id_from_ref(&refstring);
 
-            let storefront_info = config.get_storefront_info(&app_id)?;
-            if !storefront_infos.contains_key(&app_id) {
-                storefront_infos.insert(app_id.clone(), storefront_info);
+            let storefront_info = storefront_infos
+                .get(&app_id)
+                .ok_or_else(|| anyhow!("No storefront info for {app_id}"))?;
+
+            // Republished commits have already been rewritten and signed by flat-manager, so only check new uploads
+            if let (Some(_), Some(upload_signature)) = (build_id, &config.upload_signature) {
+                verify_upload_signature(&repo, upload_signature, &refstring, &checksum)?;
+            }
+
+            // On republishes, carry the build log URLs from the original publish forward
+            let previous_build = match &build {
+                Some(_) => None,
+                None => find_previous_build(&config, &repo, &refstring, &checksum)?,
+            };
+            let build = if previous_build.is_some() {
+                &previous_build
+            } else {
+                &build
+            };
+
+            if self.dry_run {
+                dry_run_reports.push(dry_run_ref(
+                    &repo,
+                    &config,
+                    storefront_info,
+                    build_id,
+                    build,
+                    &refstring,
+                    &checksum,
+                )?);
+            } else {
+                journal_records.extend(rewrite_ref(
+                    &repo,
+                    &config,
+                    storefront_info,
+                    &batch,
+                    build,
+                    &refstring,
+                    &checksum,
+                )?);
+            }
+        }
+
+        if let Some(tx) = tx {
+            tx.commit()?;
+        }
+
+        // Only record the rewrites once they've actually been committed
+        if let Some(journal) = &config.rewrite_journal {
+            if !journal_records.is_empty() {
+                append_journal(journal, &journal_records)?;
             }
-            let storefront_info = storefront_infos.get(&app_id).unwrap();
+        }
 
-            rewrite_ref(&repo, storefront_info, &build, &refstring, &checksum)?;
+        if self.dry_run {
+            dry_run_reports.sort_by(|a, b| a.refstring.cmp(&b.refstring));
+            println!("{}", serde_json::to_string_pretty(&dry_run_reports)?);
         }
 
         Ok(())
     }
 }
 
-fn rewrite_ref(
+#[derive(Debug, Serialize)]
+struct Change<T> {
+    old: T,
+    new: T,
+}
+
+/// The changes `rewrite_ref` would make to a ref, as reported by `publish --dry-run`.
+#[derive(Debug, Serialize)]
+struct DryRunReport {
+    refstring: String,
+    checksum: String,
+    /// Unified diff of the appstream catalog, or `None` if it wouldn't change (or the ref doesn't have one).
+    appstream_diff: Option<String>,
+    subsets: Option<Change<Option<Vec<String>>>>,
+    token_type: Option<Change<Option<i32>>>,
+    /// Unified diff of the whole commit metadata, including the end-of-life and provenance keys, or `None` if it
+    /// wouldn't change.
+    metadata_diff: Option<String>,
+}
+
+/// Computes the changes `rewrite_ref` would make to a ref, without writing anything to the repo.
+fn dry_run_ref(
     repo: &Repo,
+    config: &RegularConfig,
     storefront_info: &StorefrontInfo,
+    build_id: Option<i64>,
     build: &Option<BuildExtended>,
     refstring: &str,
     checksum: &str,
-) -> Result<()> {
+) -> Result<DryRunReport> {
     let app_id = app_id_from_ref(refstring);
 
-    let tx = Transaction::new(repo)?;
+    let (root, _) = repo.read_commit(checksum, Cancellable::NONE)?;
+    let appstream_diff = match load_appstream(&root, &app_id) {
+        Ok((_, content, _)) => {
+            let new_appstream = rewrite_appstream_xml(
+                &config.branding,
+                storefront_info,
+                refstring,
+                build,
+                &content,
+            )?;
+            (new_appstream != content).then(|| format_diff(&content, &new_appstream))
+        }
+        Err(_) => None,
+    };
+
+    let old_metadata = repo.load_commit(checksum)?.0.child_value(0);
+    let metadata = VariantDict::new(Some(&old_metadata));
+    let old_subsets = metadata.lookup::<Vec<String>>("xa.subsets")?;
+    let old_token_type = metadata.lookup::<i32>("xa.token-type")?;
+
+    rewrite_commit_metadata(
+        repo,
+        config,
+        storefront_info,
+        build_id,
+        build,
+        refstring,
+        &metadata,
+    )?;
+
+    let new_subsets = metadata.lookup::<Vec<String>>("xa.subsets")?;
+    let new_token_type = metadata.lookup::<i32>("xa.token-type")?;
+    let old_metadata = format_metadata(&old_metadata);
+    let new_metadata = format_metadata(&metadata.end());
+
+    Ok(DryRunReport {
+        refstring: refstring.to_string(),
+        checksum: checksum.to_string(),
+        appstream_diff,
+        subsets: (old_subsets != new_subsets).then_some(Change {
+            old: old_subsets,
+            new: new_subsets,
+        }),
+        token_type: (old_token_type != new_token_type).then_some(Change {
+            old: old_token_type,
+            new: new_token_type,
+        }),
+        metadata_diff: (old_metadata != new_metadata)
+            .then(|| format_diff(&old_metadata, &new_metadata)),
+    })
+}
+
+/// Lines of unchanged context shown around each change by `format_diff`.
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Formats the differences between two versions of a file as unified diff hunks, for logging. Returns an empty string
+/// if they're the same.
+fn format_diff(old: &str, new: &str) -> String {
+    let lines = diff::lines(old, new);
+
+    // The line numbers in the old and new file at the start of each diff line
+    let mut old_line = vec![0];
+    let mut new_line = vec![0];
+    for l in &lines {
+        let (o, n) = match l {
+            diff::Result::Left(_) => (1, 0),
+            diff::Result::Both(_, _) => (1, 1),
+            diff::Result::Right(_) => (0, 1),
+        };
+        old_line.push(old_line.last().unwrap() + o);
+        new_line.push(new_line.last().unwrap() + n);
+    }
+
+    // Group the changes into hunks, merging ones whose context would overlap
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, _) in lines
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| !matches!(l, diff::Result::Both(_, _)))
+    {
+        let start = i.saturating_sub(DIFF_CONTEXT_LINES);
+        let end = (i + DIFF_CONTEXT_LINES + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut result = String::new();
+    for (start, end) in hunks {
+        // Like diff, an empty range is numbered by the line before it
+        let range = |line: &[usize]| {
+            let count = line[end] - line[start];
+            let first = if count == 0 {
+                line[start]
+            } else {
+                line[start] + 1
+            };
+            format!("{first},{count}")
+        };
+        result.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(&old_line),
+            range(&new_line)
+        ));
+        for l in &lines[start..end] {
+            result.push_str(&match l {
+                diff::Result::Left(l) => format!("-{l}\n"),
+                diff::Result::Both(b, _) => format!(" {b}\n"),
+                diff::Result::Right(r) => format!("+{r}\n"),
+            });
+        }
+    }
+    result
+}
+
+/// Checks that an uploaded commit is signed by one of the configured keys, before the rewrite drops its signature.
+fn verify_upload_signature(
+    repo: &Repo,
+    upload_signature: &UploadSignatureConfig,
+    refstring: &str,
+    checksum: &str,
+) -> Result<()> {
+    match upload_signature {
+        UploadSignatureConfig::Gpg { keyring } => {
+            // Without a keyring directory, OSTree also trusts the system keyrings (e.g. distro keys in
+            // /usr/share/ostree/trusted.gpg.d), so pass an empty one to only accept the configured keyring
+            let keyringdir = tempfile::tempdir()?;
+            repo.verify_commit_ext(
+                checksum,
+                Some(&File::for_path(keyringdir.path())),
+                Some(&File::for_path(keyring)),
+                Cancellable::NONE,
+            )
+            .and_then(|result| result.require_valid_signature())
+            .with_context(|| format!("{refstring} ({checksum}) isn't signed by the uploader"))?;
+        }
+        UploadSignatureConfig::Ed25519 { public_keys } => {
+            let sign = Sign::by_name("ed25519")?;
+            let options = VariantDict::new(None);
+            options.insert("filename", public_keys.to_string_lossy().as_ref());
+            sign.load_pk(&options.end())?;
+            sign.commit_verify(repo, checksum, Cancellable::NONE)
+                .with_context(|| {
+                    format!("{refstring} ({checksum}) isn't signed by the uploader")
+                })?;
+        }
+    }
+
+    info!("Verified the upload signature of {refstring}");
+    Ok(())
+}
+
+/// Finds the build a republished commit originally came from, using the provenance recorded in its metadata when it
+/// was first published. The recorded log URLs are only used if both of them are there; otherwise the build is looked
+/// up in flat-manager by its recorded ID, since a partial build would drop the missing keys from the appstream data.
+/// Returns `None` if the commit doesn't have that information.
+fn find_previous_build<C: Config>(
+    config: &C,
+    repo: &Repo,
+    refstring: &str,
+    checksum: &str,
+) -> Result<Option<BuildExtended>> {
+    let metadata = repo.load_commit(checksum)?.0.child_get::<VariantDict>(0);
+
+    let branding = config.branding();
+    let build_log_url =
+        metadata.lookup::<String>(&provenance_key(branding, PROVENANCE_BUILD_LOG_URL))?;
+    let build_ref_log_url =
+        metadata.lookup::<String>(&provenance_key(branding, PROVENANCE_BUILD_REF_LOG_URL))?;
+
+    if let (Some(build_log_url), Some(build_ref_log_url)) = (build_log_url, build_ref_log_url) {
+        return Ok(Some(BuildExtended {
+            build: Build {
+                app_id: None,
+                repo: String::new(),
+                build_log_url: Some(build_log_url),
+            },
+            build_refs: vec![BuildRef {
+                ref_name: refstring.to_string(),
+                build_log_url: Some(build_ref_log_url),
+            }],
+        }));
+    }
+
+    // The log URLs weren't all recorded, but the build ID was, so ask flat-manager
+    if let Some(build_id) =
+        metadata.lookup::<i64>(&provenance_key(branding, PROVENANCE_BUILD_ID))?
+    {
+        info!("Looking up build log URLs for {refstring} from build {build_id}");
+        return Ok(Some(config.get_build_by_id(build_id)?));
+    }
+
+    Ok(None)
+}
+
+/// Rewrites a ref's commit and stages the ref update. Must be called inside a transaction; the ref only moves when
+/// the transaction is committed. Returns a journal record if the ref changed.
+fn rewrite_ref(
+    repo: &Repo,
+    config: &RegularConfig,
+    storefront_info: &StorefrontInfo,
+    batch: &JournalBatch,
+    build: &Option<BuildExtended>,
+    refstring: &str,
+    checksum: &str,
+) -> Result<Option<JournalRecord>> {
+    let app_id = app_id_from_ref(refstring);
 
     // Create a MutableTree so we can edit the commit's files
     let mtree = MutableTree::from_commit(repo, checksum)?;
 
-    rewrite_appstream_file(repo, &mtree, &app_id, storefront_info, build, refstring)?;
+    let appstream_diff = rewrite_appstream_file(
+        repo,
+        &config.branding,
+        &mtree,
+        &app_id,
+        storefront_info,
+        build,
+        refstring,
+    )?;
 
     // Write the modified MutableTree to the repository.
     let repo_file = repo.write_mtree(&mtree, Cancellable::NONE)?;
 
-    // Copy the original commit metadata. Leave out extended attributes, that's just the signature, which
-    // won't be valid when we rewrite the commit (and flat-manager will sign the resulting commit with its own key
-    // anyway)
+    // Copy the original commit metadata. The detached metadata is handled separately once the new commit exists.
     let commit_metadata = repo.load_commit(checksum)?.0;
     let metadata = commit_metadata.child_get::<VariantDict>(0);
     let subject = &commit_metadata.child_get::<String>(3);
@@ -100,7 +425,16 @@ fn rewrite_ref(
     let time = ostree::commit_get_timestamp(&commit_metadata);
     let parent = ostree::commit_get_parent(&commit_metadata).map(|x| x.to_string());
 
-    rewrite_metadata(&metadata, storefront_info)?;
+    rewrite_commit_metadata(
+        repo,
+        config,
+        storefront_info,
+        batch.build_id,
+        build,
+        refstring,
+        &metadata,
+    )?;
+    let new_metadata = metadata.end();
 
     // Write a new commit with the new dirtree but (mostly) the same metadata
     let new_checksum = repo
@@ -108,7 +442,7 @@ fn rewrite_ref(
             parent.as_deref(),
             Some(subject),
             Some(body),
-            Some(&metadata.end()),
+            Some(&new_metadata),
             repo_file.dynamic_cast_ref().unwrap(),
             time,
             Cancellable::NONE,
@@ -117,84 +451,343 @@ fn rewrite_ref(
 
     if checksum == new_checksum {
         info!("No changes to {refstring}");
-    } else {
-        info!("Rewriting ref {refstring} from {checksum} to {new_checksum}");
-        // Update the ref to point to the edited commit
-        repo.transaction_set_ref(None, refstring, Some(&new_checksum));
+        return Ok(None);
     }
 
-    tx.commit()?;
+    copy_detached_metadata(
+        repo,
+        &config.preserved_detached_metadata,
+        checksum,
+        &new_checksum,
+    )?;
+
+    verify_rewritten_commit(
+        repo,
+        config,
+        storefront_info,
+        build,
+        refstring,
+        &new_checksum,
+    )?;
+
+    info!("Rewriting ref {refstring} from {checksum} to {new_checksum}");
+    // Update the ref to point to the edited commit
+    repo.transaction_set_ref(None, refstring, Some(&new_checksum));
+
+    let old_metadata = format_metadata(&commit_metadata.child_value(0));
+    let new_metadata = format_metadata(&new_metadata);
+    let new_content_checksum =
+        ostree::commit_get_content_checksum(&repo.load_commit(&new_checksum)?.0)
+            .ok_or_else(|| anyhow!("Failed to compute the content checksum of {new_checksum}"))?
+            .to_string();
+
+    Ok(Some(JournalRecord {
+        batch: batch.clone(),
+        refstring: refstring.to_string(),
+        old_checksum: checksum.to_string(),
+        new_checksum,
+        new_content_checksum,
+        production_checksum: production_checksum(config, build, refstring)?,
+        appstream_diff,
+        metadata_diff: (old_metadata != new_metadata)
+            .then(|| format_diff(&old_metadata, &new_metadata)),
+    }))
+}
 
+/// Makes all the edits `rewrite_ref` makes to a commit's metadata: subsets, token type and end-of-life from the
+/// storefront info, plus the build provenance.
+fn rewrite_commit_metadata(
+    repo: &Repo,
+    config: &RegularConfig,
+    storefront_info: &StorefrontInfo,
+    build_id: Option<i64>,
+    build: &Option<BuildExtended>,
+    refstring: &str,
+    metadata: &VariantDict,
+) -> Result<()> {
+    let runtime = subset_runtime(repo, metadata)?;
+    rewrite_metadata(
+        metadata,
+        &config.subsets,
+        storefront_info,
+        runtime.as_deref(),
+        refstring,
+    )?;
+    // Republishes have no build ID of their own, so they carry the provenance of the original build forward
+    let provenance_build_id = match build_id {
+        Some(build_id) => Some(build_id),
+        None => metadata.lookup::<i64>(&provenance_key(&config.branding, PROVENANCE_BUILD_ID))?,
+    };
+    if let (Some(build_id), Some(build)) = (provenance_build_id, build) {
+        write_provenance(&config.branding, metadata, build_id, build, refstring);
+    }
     Ok(())
 }
 
-pub fn rewrite_appstream_file(
+/// Looks up the commit a ref currently points to in the production repo the build is published to, so `rollback` can
+/// point it back there. Returns `None` if the production repo isn't configured or doesn't have the ref yet.
+fn production_checksum(
+    config: &RegularConfig,
+    build: &Option<BuildExtended>,
+    refstring: &str,
+) -> Result<Option<String>> {
+    let Some(path) = build
+        .as_ref()
+        .and_then(|build| config.production_repos.get(&build.build.repo))
+    else {
+        return Ok(None);
+    };
+
+    let repo = Repo::new(&File::for_path(path));
+    repo.open(Cancellable::NONE)
+        .with_context(|| format!("Failed to open production repo {}", path.display()))?;
+
+    Ok(repo.resolve_rev(refstring, true)?.map(|x| x.to_string()))
+}
+
+/// Copies the allowlisted keys of a commit's detached metadata onto its rewritten commit. The GPG signature is never
+/// copied, since it won't be valid for the new commit (and flat-manager will sign the new commit with its own key
+/// anyway).
+fn copy_detached_metadata(
     repo: &Repo,
-    mtree: &MutableTree,
-    app_id: &str,
+    allowlist: &[String],
+    old_checksum: &str,
+    new_checksum: &str,
+) -> Result<()> {
+    let Some(detached) = repo.read_commit_detached_metadata(old_checksum, Cancellable::NONE)?
+    else {
+        return Ok(());
+    };
+
+    let (new, copied) = filter_detached_metadata(allowlist, &VariantDict::new(Some(&detached)));
+
+    if !copied.is_empty() {
+        info!("Keeping detached metadata: {}", copied.join(", "));
+        repo.write_commit_detached_metadata(new_checksum, Some(&new.end()), Cancellable::NONE)?;
+    }
+
+    Ok(())
+}
+
+/// Picks the allowlisted keys out of a commit's detached metadata, except for the GPG signature. Returns the new
+/// detached metadata and the keys that were kept.
+fn filter_detached_metadata<'a>(
+    allowlist: &'a [String],
+    old: &VariantDict,
+) -> (VariantDict, Vec<&'a str>) {
+    let new = VariantDict::new(None);
+    let mut copied = vec![];
+
+    for key in allowlist {
+        if key == GPG_SIGNATURES_KEY {
+            continue;
+        }
+        if let Some(value) = old.lookup_value(key, None) {
+            new.insert_value(key, &value);
+            copied.push(key.as_str());
+        }
+    }
+
+    (new, copied)
+}
+
+/// Re-reads a rewritten commit and checks that its appstream keys and metadata are the ones we meant to write, so a
+/// broken rewrite fails the publish (and aborts the transaction) instead of reaching users.
+fn verify_rewritten_commit(
+    repo: &Repo,
+    config: &RegularConfig,
     storefront_info: &StorefrontInfo,
     build: &Option<BuildExtended>,
     refstring: &str,
+    new_checksum: &str,
 ) -> Result<()> {
-    let appstream_filename = &format!("{app_id}.xml.gz");
-    let appstream_file = mtree_lookup_file(
-        mtree,
-        &["files", "share", "app-info", "xmls", appstream_filename],
-    );
+    let app_id = app_id_from_ref(refstring);
+    let (root, _) = repo.read_commit(new_checksum, Cancellable::NONE)?;
+
+    // Rewriting never adds or removes the catalog file, so if there's none now, there was none to begin with
+    let has_catalog = appstream_catalog_candidates(&app_id)
+        .iter()
+        .any(|(dir, filename, _)| {
+            root.resolve_relative_path(format!("{dir}/{filename}"))
+                .query_exists(Cancellable::NONE)
+        });
 
-    if appstream_file.is_err() {
-        return Ok(());
+    if has_catalog {
+        let (path, _, appstream) = load_appstream(&root, &app_id).with_context(|| {
+            format!("Failed to load the rewritten appstream catalog of {refstring}")
+        })?;
+        verify_appstream(
+            &config.branding,
+            storefront_info,
+            build,
+            refstring,
+            &appstream,
+        )
+        .with_context(|| format!("Rewritten appstream catalog {path} of {refstring} is wrong"))?;
+    }
+
+    let metadata = repo
+        .load_commit(new_checksum)?
+        .0
+        .child_get::<VariantDict>(0);
+    let runtime = subset_runtime(repo, &metadata)?;
+    let properties = RefProperties::new(runtime.as_deref(), storefront_info, refstring);
+
+    let subsets = metadata
+        .lookup::<Vec<String>>("xa.subsets")?
+        .unwrap_or_default();
+    let expected_subsets = list_subsets(&config.subsets, &properties);
+    if subsets != expected_subsets {
+        return Err(anyhow!(
+            "Rewritten commit of {refstring} has subsets {subsets:?}, expected {expected_subsets:?}"
+        ));
     }
 
-    let appstream_content = read_file_from_repo(repo, &appstream_file.unwrap())?;
+    let token_type = metadata.lookup::<i32>("xa.token-type")?;
+    let expected_token_type = properties.paid.then_some(1_i32.to_le());
+    if token_type != expected_token_type {
+        return Err(anyhow!(
+            "Rewritten commit of {refstring} has token type {token_type:?}, expected {expected_token_type:?}"
+        ));
+    }
+
+    Ok(())
+}
 
-    let mut s = String::new();
-    GzDecoder::new(&*appstream_content).read_to_string(&mut s)?;
+/// Checks that the keys in an appstream catalog's namespace are exactly the ones `rewrite_appstream_xml` should have
+/// set. The manifest key is skipped since it comes from upstream, and so are the build keys on republishes.
+fn verify_appstream(
+    branding: &Branding,
+    storefront_info: &StorefrontInfo,
+    build: &Option<BuildExtended>,
+    refstring: &str,
+    appstream: &Element,
+) -> Result<()> {
+    let component = appstream
+        .find("component")
+        .ok_or_else(|| anyhow!("No <component> tag"))?;
+
+    let namespace = branding.namespace.to_lowercase();
+    let manifest_key = format!("{namespace}::manifest");
+    let build_prefix = format!("{namespace}::build::");
+
+    let mut values: Vec<(String, String)> = component
+        .find_all("custom")
+        .flat_map(|custom| custom.find_all("value"))
+        .filter_map(|value| {
+            let key = value.get_attr("key")?.to_lowercase();
+            Some((key, value.text().to_string()))
+        })
+        .filter(|(key, _)| {
+            key.starts_with(&format!("{namespace}::"))
+                && *key != manifest_key
+                && !(build.is_none() && key.starts_with(&build_prefix))
+        })
+        .collect();
+    let mut expected = appstream_values(branding, storefront_info, build, refstring);
 
-    let new_appstream = rewrite_appstream_xml(storefront_info, refstring, build, &s)?;
+    values.sort();
+    expected.sort();
+
+    if values != expected {
+        return Err(anyhow!("Expected the keys {expected:?}, found {values:?}"));
+    }
+
+    Ok(())
+}
+
+/// Formats a commit metadata dict with one key per line, for diffing.
+fn format_metadata(metadata: &Variant) -> String {
+    let mut lines: Vec<String> = metadata
+        .iter()
+        .map(|entry| {
+            format!(
+                "{}: {}",
+                entry.child_value(0).str().unwrap_or_default(),
+                entry.child_value(1).print(true)
+            )
+        })
+        .collect();
+    lines.sort();
+    lines.join("\n")
+}
+
+pub fn rewrite_appstream_file(
+    repo: &Repo,
+    branding: &Branding,
+    mtree: &MutableTree,
+    app_id: &str,
+    storefront_info: &StorefrontInfo,
+    build: &Option<BuildExtended>,
+    refstring: &str,
+) -> Result<Option<String>> {
+    // Use the first catalog that exists, in the same order validation looks for them
+    let found =
+        appstream_catalog_candidates(app_id)
+            .into_iter()
+            .find_map(|(dir, filename, encoding)| {
+                let dir: Vec<&str> = dir.split('/').collect();
+                let path = [dir.as_slice(), &[filename.as_str()]].concat();
+                mtree_lookup_file(mtree, &path)
+                    .ok()
+                    .map(|file_checksum| (dir, filename.clone(), encoding, file_checksum))
+            });
+
+    let Some((appstream_dir, appstream_filename, encoding, appstream_file)) = found else {
+        if is_primary_ref(refstring, &branding.appid_skiplist) {
+            warn!("No appstream catalog found for {refstring}, so it won't be rewritten");
+        }
+        return Ok(None);
+    };
+
+    let appstream_content = read_file_from_repo(repo, &appstream_file)?;
+    let s = encoding.decode(&appstream_content)?;
+
+    let new_appstream = rewrite_appstream_xml(branding, storefront_info, refstring, build, &s)?;
 
     if new_appstream == s {
         // If the appstream contents didn't change, we shouldn't bother rewriting the file
-        return Ok(());
-    } else {
-        let difference = diff::lines(&s, &new_appstream)
-            .iter()
-            .map(|l| match l {
-                diff::Result::Left(l) => format!("-{l}\n"),
-                diff::Result::Both(b, _) => format!(" {b}\n"),
-                diff::Result::Right(r) => format!("+{r}\n"),
-            })
-            .collect::<String>();
-        info!("Changes to {}: {}", appstream_filename, difference);
+        return Ok(None);
     }
 
-    // gzip encode the new appstream file
-    let mut s = vec![];
-    GzEncoder::new(&mut s, Compression::default()).write_all(new_appstream.as_bytes())?;
+    let difference = format_diff(&s, &new_appstream);
+    info!("Changes to {}: {}", appstream_filename, difference);
+
+    // Encode the new appstream file the same way as the original
+    let s = encoding.encode(&new_appstream)?;
 
     // Write the new appstream file to the repo
     let checksum = repo.write_regfile_inline(None, 0, 0, 0o100644, None, &s, Cancellable::NONE)?;
 
     // Edit the MutableTree with a reference to the new appstream file
-    mtree_lookup(mtree, &["files", "share", "app-info", "xmls"])?
+    mtree_lookup(mtree, &appstream_dir)?
         .1
         .ok_or(anyhow!("file not found"))?
-        .replace_file(&format!("{app_id}.xml.gz"), &checksum)?;
+        .replace_file(&appstream_filename, &checksum)?;
 
-    Ok(())
+    Ok(Some(difference))
 }
 
+/// Updates the custom keys under the configured namespace (`flathub::` by default) and the bugtracker URL in an
+/// appstream catalog. Everything else in the document is left byte-for-byte identical, so the only differences are
+/// the semantic ones.
 pub fn rewrite_appstream_xml(
+    branding: &Branding,
     storefront_info: &StorefrontInfo,
     refstring: &str,
     build: &Option<BuildExtended>,
     original_appstream: &str,
 ) -> Result<String> {
-    let mut changed = false;
-
-    let mut root = Element::from_reader(original_appstream.as_bytes())?;
-
-    let mut components: Vec<_> = root.children_mut().collect();
+    let doc = Document::parse_with_options(
+        original_appstream,
+        ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        },
+    )?;
+    let mut editor = XmlEditor::new(original_appstream);
+
+    let components: Vec<_> = element_children(doc.root_element()).collect();
     if components.len() != 1 {
         return Err(anyhow!(
             "Expected exactly 1 <component> tag, found {}",
@@ -202,124 +795,162 @@ pub fn rewrite_appstream_xml(
         ));
     }
 
-    let component = &mut components[0];
-
-    // Delete all existing "flathub::" keys
-    for metadata_tag in component.find_all_mut("custom") {
-        metadata_tag.retain_children(|value: &Element| {
-            if let Some(key) = value.get_attr("key") {
-                if key.to_lowercase().starts_with("flathub::") {
-                    #[allow(clippy::if_same_then_else)]
-                    if key.to_lowercase() == "flathub::manifest" {
-                        /* Preserve the flathub::manifest key, it is allowed to be set by upstream */
-                        true
-                    } else if key.to_lowercase().starts_with("flathub::build::") && build.is_none()
-                    {
-                        /* On republishes, preserve the previous build log URL */
-                        true
-                    } else {
-                        changed = true;
-                        false
-                    }
-                } else {
-                    true
-                }
+    let component = components[0];
+    let customs: Vec<_> = element_children(component)
+        .filter(|n| n.has_tag_name("custom"))
+        .collect();
+
+    let has_key = |node: Node, key: &str| {
+        node.attribute("key").map(|k| k.to_lowercase()) == Some(key.to_string())
+    };
+
+    let namespace = branding.namespace.to_lowercase();
+    let manifest_key = format!("{namespace}::manifest");
+
+    // Move the manifest key from the old <metadata> tag to <custom>
+    let manifest_url: Option<String> = {
+        let mut found = None;
+
+        for metadata_tag in element_children(component).filter(|n| n.has_tag_name("metadata")) {
+            let (manifests, others): (Vec<_>, Vec<_>) =
+                element_children(metadata_tag).partition(|value| has_key(*value, &manifest_key));
+
+            if found.is_none() {
+                found = manifests
+                    .first()
+                    .map(|value| value.text().unwrap_or_default().to_owned());
+            }
+
+            if others.is_empty() {
+                editor.remove_element(metadata_tag);
             } else {
-                true
+                for value in manifests {
+                    editor.remove_element(value);
+                }
             }
-        });
-    }
+        }
 
-    fn find_element<'a>(
-        parent: &'a mut Element,
-        tag: &'a str,
-        attr: Option<(&'_ str, &'_ str)>,
-    ) -> Option<&'a mut Element> {
-        let existing = if let Some((key, val)) = attr {
-            parent
-                .find_all_mut(tag)
-                .find(|el| el.get_attr(key) == Some(val))
-        } else {
-            parent.find_mut(tag)
-        };
+        found
+    };
 
-        existing
-    }
+    let mut values: Vec<(String, String)> = vec![];
 
-    fn find_or_create_element<'a>(
-        parent: &'a mut Element,
-        tag: &'a str,
-        attr: Option<(&'_ str, &'_ str)>,
-    ) -> &'a mut Element {
-        if find_element(parent, tag, attr).is_some() {
-            // running find_element twice is a borrow checker workaround
-            find_element(parent, tag, attr).unwrap()
-        } else {
-            let new_tag = parent.append_new_child(tag);
-            new_tag.set_tail("\n  ");
-            if let Some((key, val)) = attr {
-                new_tag.set_attr(key, val);
-            }
-            new_tag
+    if let Some(url) = manifest_url {
+        let has_custom_manifest = customs
+            .iter()
+            .flat_map(|custom| element_children(*custom))
+            .any(|value| has_key(value, &manifest_key));
+        if !has_custom_manifest {
+            values.push((manifest_key.clone(), url));
         }
     }
 
-    let manifest_url: Option<String> = {
-        let mut found = None;
+    values.extend(appstream_values(
+        branding,
+        storefront_info,
+        build,
+        refstring,
+    ));
+
+    // Update the existing keys in the namespace in place, and delete the ones that are no longer set
+    let mut found = vec![false; values.len()];
+    for value_tag in customs.iter().flat_map(|custom| element_children(*custom)) {
+        let Some(key) = value_tag.attribute("key") else {
+            continue;
+        };
+        let key = key.to_lowercase();
 
-        for metadata_tag in component.find_all_mut("metadata") {
-            metadata_tag.retain_children(|value: &Element| {
-                if value.get_attr("key").map(|k| k.to_lowercase())
-                    == Some("flathub::manifest".to_string())
-                {
-                    if found.is_none() {
-                        found = Some(value.text().to_owned());
-                    }
-                    changed = true;
-                    false
-                } else {
-                    true
-                }
-            });
+        if !key.starts_with(&format!("{namespace}::")) || key == manifest_key {
+            /* Preserve the manifest key, it is allowed to be set by upstream */
+            continue;
+        }
+        if key.starts_with(&format!("{namespace}::build::")) && build.is_none() {
+            /* On republishes, preserve the previous build log URL */
+            continue;
         }
 
-        component.retain_children(|child: &Element| {
-            if child.tag().name() == "metadata" {
-                let keep = child.child_count() > 0;
-                if !keep {
-                    changed = true;
+        match values
+            .iter()
+            .enumerate()
+            .position(|(i, (k, _))| *k == key && !found[i])
+        {
+            Some(i) => {
+                found[i] = true;
+                if value_tag.text().unwrap_or_default() != values[i].1 {
+                    editor.set_text(value_tag, &values[i].1);
                 }
-                keep
-            } else {
-                true
             }
-        });
+            None => editor.remove_element(value_tag),
+        }
+    }
 
-        found
-    };
+    // Add the keys that weren't there yet
+    let new_values: Vec<_> = values
+        .iter()
+        .zip(found)
+        .filter(|(_, found)| !found)
+        .map(|((key, value), _)| xml_edit::element("value", &[("key", key)], value))
+        .collect();
+
+    if !new_values.is_empty() {
+        match customs.first() {
+            Some(custom) => editor.append_children(*custom, &new_values),
+            None => editor.append_element(component, "custom", &new_values),
+        }
+    }
 
-    if let Some(url) = manifest_url {
-        let custom = find_or_create_element(component, "custom", None);
-        if find_element(custom, "value", Some(("key", "flathub::manifest"))).is_none() {
-            find_or_create_element(custom, "value", Some(("key", "flathub::manifest")))
-                .set_text(&url);
-            changed = true;
+    let app_id = refstring.split('/').nth(1).unwrap_or_default();
+    let bugtracker_url = branding.bugtracker_url(app_id);
+
+    if let Some(bugtracker_url) = bugtracker_url.filter(|_| {
+        !storefront_info
+            .verification
+            .as_ref()
+            .is_some_and(|v| v.verified)
+    }) {
+        let existing = element_children(component)
+            .find(|n| n.has_tag_name("url") && n.attribute("type") == Some("bugtracker"));
+
+        match existing {
+            Some(url_tag) => {
+                if url_tag.text().unwrap_or_default() != bugtracker_url {
+                    editor.set_text(url_tag, &bugtracker_url);
+                }
+            }
+            None => editor.append_children(
+                component,
+                &[xml_edit::element(
+                    "url",
+                    &[("type", "bugtracker")],
+                    &bugtracker_url,
+                )],
+            ),
         }
     }
 
+    Ok(editor.finish())
+}
+
+/// The keys `rewrite_appstream_xml` sets in the `<custom>` tag, apart from the manifest, in order.
+fn appstream_values(
+    branding: &Branding,
+    storefront_info: &StorefrontInfo,
+    build: &Option<BuildExtended>,
+    refstring: &str,
+) -> Vec<(String, String)> {
+    let mut values = vec![];
+    let namespace = branding.namespace.to_lowercase();
+
     let mut set_value = |key: &str, value: Option<&str>| {
         if let Some(value) = value {
-            let custom = find_or_create_element(component, "custom", None);
-            find_or_create_element(custom, "value", Some(("key", key))).set_text(value);
-
-            changed = true;
+            values.push((format!("{namespace}::{key}"), value.to_owned()));
         }
     };
 
     // Add verification tags
     if let Some(verification) = &storefront_info.verification {
         set_value(
-            "flathub::verification::verified",
+            "verification::verified",
             Some(if verification.verified {
                 "true"
             } else {
@@ -328,28 +959,29 @@ pub fn rewrite_appstream_xml(
         );
 
         set_value(
-            "flathub::verification::timestamp",
-            verification.timestamp.as_deref(),
+            "verification::timestamp",
+            verification
+                .timestamp
+                .as_ref()
+                .map(|x| x.to_string())
+                .as_deref(),
         );
         set_value(
-            "flathub::verification::method",
-            verification.method.as_deref(),
+            "verification::method",
+            verification.method.as_ref().map(|x| x.as_str()),
         );
         set_value(
-            "flathub::verification::login_name",
+            "verification::login_name",
             verification.login_name.as_deref(),
         );
         set_value(
-            "flathub::verification::login_provider",
+            "verification::login_provider",
             verification.login_provider.as_deref(),
         );
+        set_value("verification::website", verification.website.as_deref());
         set_value(
-            "flathub::verification::website",
-            verification.website.as_deref(),
-        );
-        set_value(
-            "flathub::verification::login_is_organization",
-            Some(if verification.login_is_organization.is_some() {
+            "verification::login_is_organization",
+            Some(if verification.login_is_organization.unwrap_or(false) {
                 "true"
             } else {
                 "false"
@@ -360,14 +992,14 @@ pub fn rewrite_appstream_xml(
     // Add pricing tags
     if let Some(pricing) = &storefront_info.pricing {
         set_value(
-            "flathub::pricing::recommended_donation",
+            "pricing::recommended_donation",
             pricing
                 .recommended_donation
                 .map(|x| x.to_string())
                 .as_deref(),
         );
         set_value(
-            "flathub::pricing::minimum_payment",
+            "pricing::minimum_payment",
             pricing.minimum_payment.map(|x| x.to_string()).as_deref(),
         );
     }
@@ -375,10 +1007,7 @@ pub fn rewrite_appstream_xml(
     // Add build log tags
     if let Some(build) = build {
         if let Some(build_log_url) = &build.build.build_log_url {
-            set_value(
-                "flathub::build::build_log_url",
-                Some(build_log_url.as_str()),
-            );
+            set_value("build::build_log_url", Some(build_log_url.as_str()));
         }
         if let Some(build_ref_log_url) = &build
             .build_refs
@@ -386,35 +1015,27 @@ pub fn rewrite_appstream_xml(
             .find(|x| x.ref_name == refstring)
             .and_then(|x| x.build_log_url.as_ref())
         {
-            set_value(
-                "flathub::build::build_ref_log_url",
-                Some(build_ref_log_url.as_str()),
-            );
+            set_value("build::build_ref_log_url", Some(build_ref_log_url.as_str()));
         }
     }
 
-    let app_id = refstring.split('/').nth(1).unwrap_or("flathub");
-
-    if !storefront_info
-        .verification
-        .as_ref()
-        .is_some_and(|v| v.verified)
-    {
-        let bugtracker_url = find_or_create_element(component, "url", Some(("type", "bugtracker")));
-        bugtracker_url.set_text(format!("https://github.com/flathub/{}/issues", app_id));
-        changed = true;
-    }
+    values
+}
 
-    if changed {
-        Ok(root.to_string()?)
-    } else {
-        Ok(original_appstream.to_string())
-    }
+fn element_children<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(|n| n.is_element())
 }
 
 /// Edits a commit's metadata according to the given storefront info.
-pub fn rewrite_metadata(metadata: &VariantDict, storefront_info: &StorefrontInfo) -> Result<()> {
-    let subsets = list_subsets(storefront_info);
+pub fn rewrite_metadata(
+    metadata: &VariantDict,
+    subset_rules: &[SubsetRule],
+    storefront_info: &StorefrontInfo,
+    runtime: Option<&str>,
+    refstring: &str,
+) -> Result<()> {
+    let properties = RefProperties::new(runtime, storefront_info, refstring);
+    let subsets = list_subsets(subset_rules, &properties);
 
     if subsets.is_empty() {
         metadata.remove("xa.subsets");
@@ -423,54 +1044,200 @@ pub fn rewrite_metadata(metadata: &VariantDict, storefront_info: &StorefrontInfo
         metadata.insert("xa.subsets", &subsets);
     }
 
-    let is_paid = storefront_info
-        .pricing
-        .as_ref()
-        .map(|pricing| {
-            pricing.recommended_donation.is_some_and(|x| x > 0)
-                || pricing.minimum_payment.is_some_and(|x| x > 0)
-        })
-        .unwrap_or(false);
-
-    if is_paid {
+    if properties.paid {
         info!("Setting token type to 1");
         metadata.insert("xa.token-type", 1_i32.to_le());
     } else {
         metadata.remove("xa.token-type");
     }
 
+    // Mark the app as end-of-life if the backend says so. If it doesn't, keep whatever the upload had, since
+    // developers can also set these in their manifest.
+    if let Some(lifecycle) = &storefront_info.lifecycle {
+        if let Some(reason) = &lifecycle.end_of_life {
+            info!("Setting end-of-life: {reason}");
+            metadata.insert("xa.end-of-life", reason.as_str());
+        }
+        if let Some(rebase) = &lifecycle.end_of_life_rebase {
+            let rebase = rebase_ref_id(refstring, rebase);
+            info!("Setting end-of-life rebase: {rebase}");
+            metadata.insert("xa.end-of-life-rebase", rebase.as_str());
+        }
+    }
+
     Ok(())
 }
 
-/// Lists all the subsets that we should add to a commit, based on the given storefront info.
-fn list_subsets(storefront_info: &StorefrontInfo) -> Vec<String> {
-    let mut subsets = vec![];
+/// Gets the ID a ref should be rebased to when its app is renamed. Refs like `.Locale` and `.Debug` keep their suffix.
+fn rebase_ref_id(refstring: &str, new_app_id: &str) -> String {
+    let ref_id = refstring.split('/').nth(1).unwrap_or_default();
+    let suffix = ref_id
+        .strip_prefix(&app_id_from_ref(refstring))
+        .unwrap_or_default();
+    format!("{new_app_id}{suffix}")
+}
 
-    let verified = storefront_info
-        .verification
-        .as_ref()
-        .is_some_and(|x| x.verified);
+/// Records which flat-manager build a commit came from in its metadata, so it can be traced without parsing appstream.
+/// On republishes, this is called with the original build, so the provenance is kept.
+fn write_provenance(
+    branding: &Branding,
+    metadata: &VariantDict,
+    build_id: i64,
+    build: &BuildExtended,
+    refstring: &str,
+) {
+    let build_ref_log_url = build
+        .build_refs
+        .iter()
+        .find(|x| x.ref_name == refstring)
+        .and_then(|x| x.build_log_url.as_deref());
+    let key = |name| provenance_key(branding, name);
+
+    metadata.insert(&key(PROVENANCE_BUILD_ID), build_id);
+    metadata.insert(&key(PROVENANCE_HOOKS_VERSION), hooks_version());
+
+    match &build.build.build_log_url {
+        Some(url) => metadata.insert(&key(PROVENANCE_BUILD_LOG_URL), url.as_str()),
+        None => {
+            metadata.remove(&key(PROVENANCE_BUILD_LOG_URL));
+        }
+    }
+    match build_ref_log_url {
+        Some(url) => metadata.insert(&key(PROVENANCE_BUILD_REF_LOG_URL), url),
+        None => {
+            metadata.remove(&key(PROVENANCE_BUILD_REF_LOG_URL));
+        }
+    }
+}
+
+/// The full commit metadata key for a provenance field, e.g. `flathub.build.id`.
+fn provenance_key(branding: &Branding, name: &str) -> String {
+    format!("{}.{name}", branding.namespace)
+}
+
+/// What a commit's flatpak metadata (`xa.metadata`) says about its runtime.
+#[derive(Debug, PartialEq, Eq)]
+enum FlatpakRuntime {
+    /// The runtime ID (without arch and branch) of an app.
+    Runtime(String),
+    /// The ref of the app that a related ref, like `.Locale` or `.Debug`, extends.
+    ExtensionOf(String),
+    Unknown,
+}
+
+fn flatpak_runtime(metadata: &VariantDict) -> Result<FlatpakRuntime> {
+    let Some(flatpak_metadata) = metadata.lookup::<String>("xa.metadata")? else {
+        return Ok(FlatpakRuntime::Unknown);
+    };
 
-    let floss = storefront_info.is_free_software.is_some_and(|x| x);
+    let keyfile = KeyFile::new();
+    keyfile.load_from_data(&flatpak_metadata, KeyFileFlags::NONE)?;
 
-    if verified {
-        subsets.push("verified".to_string());
+    if let Ok(runtime) = keyfile.string("Application", "runtime") {
+        return Ok(runtime
+            .split('/')
+            .next()
+            .map_or(FlatpakRuntime::Unknown, |id| {
+                FlatpakRuntime::Runtime(id.to_string())
+            }));
     }
-    if floss {
-        subsets.push("floss".to_string());
+    if let Ok(extension_of) = keyfile.string("ExtensionOf", "ref") {
+        return Ok(FlatpakRuntime::ExtensionOf(extension_of.to_string()));
     }
-    if verified && floss {
-        subsets.push("verified_floss".to_string());
+
+    Ok(FlatpakRuntime::Unknown)
+}
+
+/// The runtime ID that subset rules match a ref by. Related refs like `.Locale` and `.Debug` have no runtime of their
+/// own, so they get the runtime of the app they extend, which puts them in the same subsets as the app. Otherwise a
+/// subset-filtered remote could install the app but not its related refs.
+fn subset_runtime(repo: &Repo, metadata: &VariantDict) -> Result<Option<String>> {
+    match flatpak_runtime(metadata)? {
+        FlatpakRuntime::Runtime(runtime) => Ok(Some(runtime)),
+        FlatpakRuntime::ExtensionOf(app_ref) => {
+            let Some(checksum) = repo.resolve_rev(&app_ref, true)? else {
+                return Ok(None);
+            };
+            let app_metadata = repo.load_commit(&checksum)?.0.child_get::<VariantDict>(0);
+            match flatpak_runtime(&app_metadata)? {
+                FlatpakRuntime::Runtime(runtime) => Ok(Some(runtime)),
+                _ => Ok(None),
+            }
+        }
+        FlatpakRuntime::Unknown => Ok(None),
+    }
+}
+
+/// The properties of a ref that subset rules can match on.
+struct RefProperties {
+    app_id: String,
+    arch: String,
+    branch: String,
+    /// The runtime ID of the app, see `subset_runtime`.
+    runtime: Option<String>,
+    verified: bool,
+    free_software: bool,
+    paid: bool,
+}
+
+impl RefProperties {
+    fn new(runtime: Option<&str>, storefront_info: &StorefrontInfo, refstring: &str) -> Self {
+        let parts: Vec<&str> = refstring.split('/').collect();
+
+        Self {
+            app_id: app_id_from_ref(refstring),
+            arch: parts.get(2).unwrap_or(&"").to_string(),
+            branch: parts.get(3).unwrap_or(&"").to_string(),
+            runtime: runtime.map(str::to_string),
+            verified: storefront_info
+                .verification
+                .as_ref()
+                .is_some_and(|x| x.verified),
+            free_software: storefront_info.is_free_software.is_some_and(|x| x),
+            paid: storefront_info.pricing.as_ref().is_some_and(|pricing| {
+                pricing.recommended_donation.is_some_and(|x| x > 0)
+                    || pricing.minimum_payment.is_some_and(|x| x > 0)
+            }),
+        }
+    }
+}
+
+/// Lists all the subsets that we should add to a commit, in the order the rules are given.
+fn list_subsets(rules: &[SubsetRule], properties: &RefProperties) -> Vec<String> {
+    let mut subsets: Vec<String> = vec![];
+
+    for rule in rules {
+        if subset_rule_matches(rule, properties) && !subsets.contains(&rule.name) {
+            subsets.push(rule.name.clone());
+        }
     }
 
     subsets
 }
 
+fn subset_rule_matches(rule: &SubsetRule, properties: &RefProperties) -> bool {
+    let flag_matches = |condition: Option<bool>, value: bool| condition.is_none_or(|x| x == value);
+    let list_matches =
+        |list: &[String], value: &str| list.is_empty() || list.iter().any(|x| x == value);
+    let pattern_matches = |patterns: &[String], value: Option<&str>| {
+        patterns.is_empty()
+            || value.is_some_and(|value| patterns.iter().any(|pattern| glob_match(pattern, value)))
+    };
+
+    flag_matches(rule.verified, properties.verified)
+        && flag_matches(rule.free_software, properties.free_software)
+        && flag_matches(rule.paid, properties.paid)
+        && pattern_matches(&rule.app_ids, Some(&properties.app_id))
+        && list_matches(&rule.arches, &properties.arch)
+        && list_matches(&rule.branches, &properties.branch)
+        && pattern_matches(&rule.runtimes, properties.runtime.as_deref())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        job_utils::{Build, BuildRef},
-        storefront::{PricingInfo, VerificationInfo},
+        config::default_subsets,
+        storefront::{PricingInfo, VerificationInfo, VerificationMethod, VerificationTimestamp},
     };
 
     use super::*;
@@ -488,8 +1255,11 @@ mod tests {
             }),
             pricing: None,
             is_free_software: Some(true),
+            lifecycle: None,
         };
-        let subsets = list_subsets(&storefront_info);
+        let properties =
+            RefProperties::new(None, &storefront_info, "app/org.flatpak.Test/x86_64/stable");
+        let subsets = list_subsets(&default_subsets(), &properties);
 
         assert_eq!(vec!["verified", "floss", "verified_floss"], subsets);
     }
@@ -500,12 +1270,231 @@ mod tests {
             verification: None,
             pricing: None,
             is_free_software: Some(false),
+            lifecycle: None,
         };
-        let subsets = list_subsets(&storefront_info);
+        let properties =
+            RefProperties::new(None, &storefront_info, "app/org.flatpak.Test/x86_64/stable");
+        let subsets = list_subsets(&default_subsets(), &properties);
 
         assert!(subsets.is_empty());
     }
 
+    #[test]
+    fn test_list_subsets_rules() {
+        let storefront_info = StorefrontInfo {
+            verification: None,
+            pricing: Some(PricingInfo {
+                recommended_donation: None,
+                minimum_payment: Some(5),
+            }),
+            is_free_software: Some(false),
+            lifecycle: None,
+        };
+        let properties = RefProperties::new(
+            Some("org.gnome.Platform"),
+            &storefront_info,
+            "app/org.flatpak.Test/aarch64/stable",
+        );
+
+        let rule = |name: &str| SubsetRule {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let rules = vec![
+            SubsetRule {
+                paid: Some(true),
+                ..rule("paid")
+            },
+            SubsetRule {
+                arches: vec!["aarch64".to_string()],
+                runtimes: vec!["org.gnome.*".to_string()],
+                ..rule("mobile-friendly")
+            },
+            SubsetRule {
+                app_ids: vec!["org.flatpak.*".to_string()],
+                branches: vec!["beta".to_string()],
+                ..rule("flatpak-beta")
+            },
+            SubsetRule {
+                verified: Some(true),
+                ..rule("verified")
+            },
+        ];
+
+        assert_eq!(
+            list_subsets(&rules, &properties),
+            vec!["paid", "mobile-friendly"]
+        );
+    }
+
+    #[test]
+    fn test_flatpak_runtime() {
+        let runtime = |flatpak_metadata: Option<&str>| {
+            let metadata = VariantDict::new(None);
+            if let Some(flatpak_metadata) = flatpak_metadata {
+                metadata.insert("xa.metadata", flatpak_metadata);
+            }
+            flatpak_runtime(&metadata).unwrap()
+        };
+
+        assert_eq!(
+            runtime(Some(
+                "[Application]\nname=org.flatpak.Test\nruntime=org.gnome.Platform/aarch64/47\n"
+            )),
+            FlatpakRuntime::Runtime("org.gnome.Platform".to_string())
+        );
+        /* Related refs point to their app, so they can be put in the same subsets */
+        assert_eq!(
+            runtime(Some(
+                "[Runtime]\nname=org.flatpak.Test.Locale\n\n[ExtensionOf]\nref=app/org.flatpak.Test/aarch64/stable\n"
+            )),
+            FlatpakRuntime::ExtensionOf("app/org.flatpak.Test/aarch64/stable".to_string())
+        );
+        assert_eq!(
+            runtime(Some("[Runtime]\nname=org.gnome.Platform\n")),
+            FlatpakRuntime::Unknown
+        );
+        assert_eq!(runtime(None), FlatpakRuntime::Unknown);
+    }
+
+    #[test]
+    fn test_format_diff() {
+        let old = (1..=20)
+            .map(|i| format!("line {i}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let new = old
+            .replace("line 2\n", "line 2 changed\n")
+            .replace("line 15\n", "")
+            .replace("line 20", "line 20\nline 21");
+
+        assert_eq!(
+            format_diff(&old, &new),
+            "@@ -1,5 +1,5 @@\n line 1\n-line 2\n+line 2 changed\n line 3\n line 4\n line 5\n\
+             @@ -12,9 +12,9 @@\n line 12\n line 13\n line 14\n-line 15\n line 16\n line 17\n line 18\n line 19\n \
+             line 20\n+line 21\n"
+        );
+        assert_eq!(format_diff(&old, &old), "");
+    }
+
+    #[test]
+    fn test_filter_detached_metadata() {
+        let old = VariantDict::new(None);
+        old.insert(GPG_SIGNATURES_KEY, "signature");
+        old.insert("xa.kept", "kept");
+        old.insert("xa.dropped", "dropped");
+
+        /* The GPG signature is dropped even if it's allowlisted */
+        let allowlist = vec![
+            GPG_SIGNATURES_KEY.to_string(),
+            "xa.kept".to_string(),
+            "xa.missing".to_string(),
+        ];
+        let (new, copied) = filter_detached_metadata(&allowlist, &old);
+
+        assert_eq!(copied, vec!["xa.kept"]);
+        assert_eq!(
+            new.lookup::<String>("xa.kept").unwrap(),
+            Some("kept".to_string())
+        );
+        assert!(!new.contains(GPG_SIGNATURES_KEY));
+        assert!(!new.contains("xa.dropped"));
+    }
+
+    #[test]
+    fn test_provenance_key() {
+        assert_eq!(
+            provenance_key(&Branding::default(), PROVENANCE_BUILD_ID),
+            "flathub.build.id"
+        );
+
+        let branding = Branding {
+            namespace: "example".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            provenance_key(&branding, PROVENANCE_BUILD_LOG_URL),
+            "example.build.log-url"
+        );
+    }
+
+    #[test]
+    fn test_write_provenance() {
+        let branding = Branding {
+            namespace: "example".to_string(),
+            ..Default::default()
+        };
+        let refstring = "app/org.flatpak.Test/x86_64/stable";
+        let build = BuildExtended {
+            build: Build {
+                app_id: None,
+                repo: "stable".to_string(),
+                build_log_url: Some("https://example.com/build".to_string()),
+            },
+            build_refs: vec![BuildRef {
+                ref_name: refstring.to_string(),
+                build_log_url: Some("https://example.com/build/x86_64".to_string()),
+            }],
+        };
+
+        let metadata = VariantDict::new(None);
+        write_provenance(&branding, &metadata, 42, &build, refstring);
+
+        assert_eq!(
+            metadata.lookup::<i64>("example.build.id").unwrap(),
+            Some(42)
+        );
+        assert_eq!(
+            metadata.lookup::<String>("example.build.log-url").unwrap(),
+            Some("https://example.com/build".to_string())
+        );
+        assert_eq!(
+            metadata
+                .lookup::<String>("example.build.ref-log-url")
+                .unwrap(),
+            Some("https://example.com/build/x86_64".to_string())
+        );
+        assert_eq!(
+            metadata
+                .lookup::<String>("example.build.hooks-version")
+                .unwrap(),
+            Some(hooks_version().to_string())
+        );
+        assert!(!metadata.contains("flathub.build.id"));
+
+        /* Log URLs the build doesn't have are removed rather than left over from an earlier publish */
+        let build = BuildExtended {
+            build: Build {
+                build_log_url: None,
+                ..build.build
+            },
+            build_refs: vec![],
+        };
+        write_provenance(&branding, &metadata, 43, &build, refstring);
+
+        assert_eq!(
+            metadata.lookup::<i64>("example.build.id").unwrap(),
+            Some(43)
+        );
+        assert!(!metadata.contains("example.build.log-url"));
+        assert!(!metadata.contains("example.build.ref-log-url"));
+    }
+
+    #[test]
+    fn test_rebase_ref_id() {
+        assert_eq!(
+            rebase_ref_id("app/org.flatpak.Old/x86_64/stable", "org.flatpak.New"),
+            "org.flatpak.New"
+        );
+        assert_eq!(
+            rebase_ref_id(
+                "runtime/org.flatpak.Old.Locale/x86_64/stable",
+                "org.flatpak.New"
+            ),
+            "org.flatpak.New.Locale"
+        );
+    }
+
     #[test]
     fn test_rewrite_appstream_xml_1() {
         let original_appstream = r#"<?xml version="1.0" encoding="utf-8"?>
@@ -519,16 +1508,18 @@ mod tests {
         let storefront_info = StorefrontInfo {
             verification: Some(VerificationInfo {
                 verified: true,
-                timestamp: Some("2023-01-01T00:00:00".to_string()),
-                method: Some("website".to_string()),
+                timestamp: Some(VerificationTimestamp::parse("2023-01-01T00:00:00").unwrap()),
+                method: Some(VerificationMethod::Website),
                 website: Some("example.com".to_string()),
                 ..Default::default()
             }),
             pricing: None,
             is_free_software: None,
+            lifecycle: None,
         };
 
         let result = rewrite_appstream_xml(
+            &Branding::default(),
             &storefront_info,
             "app/org.flatpak.Test/x86_64/stable",
             &Some(BuildExtended {
@@ -579,9 +1570,11 @@ mod tests {
                 recommended_donation: Some(1),
             }),
             is_free_software: None,
+            lifecycle: None,
         };
 
         let result = rewrite_appstream_xml(
+            &Branding::default(),
             &storefront_info,
             "app/org.flatpak.Test/x86_64/stable",
             &Some(BuildExtended {
@@ -640,9 +1633,11 @@ mod tests {
                 recommended_donation: None,
             }),
             is_free_software: None,
+            lifecycle: None,
         };
 
         let result = rewrite_appstream_xml(
+            &Branding::default(),
             &storefront_info,
             "app/org.flatpak.Test/x86_64/master",
             &Some(BuildExtended {
@@ -671,6 +1666,76 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_rewrite_appstream_xml_preserves_formatting() {
+        let original_appstream = r#"<?xml version='1.0' encoding='UTF-8'?>
+<!-- Generated by appstream-compose -->
+<components version="0.16" origin="flatpak">
+	<component type="desktop-application">
+		<id>org.flatpak.Test</id>
+		<name xml:lang="de">Test &amp; mehr</name>
+		<custom>
+			<value key="flathub::verification::verified">true</value>
+			<value key="flathub::pricing::minimum_payment">5</value>
+			<value key="x-other">kept</value>
+		</custom>
+	</component>
+</components>
+"#;
+
+        let storefront_info = StorefrontInfo {
+            verification: Some(VerificationInfo {
+                verified: true,
+                method: Some(VerificationMethod::Website),
+                ..Default::default()
+            }),
+            pricing: None,
+            is_free_software: None,
+            lifecycle: None,
+        };
+
+        let result = rewrite_appstream_xml(
+            &Branding::default(),
+            &storefront_info,
+            "app/org.flatpak.Test/x86_64/stable",
+            &None,
+            original_appstream,
+        )
+        .unwrap();
+
+        assert_eq!(
+            result,
+            r#"<?xml version='1.0' encoding='UTF-8'?>
+<!-- Generated by appstream-compose -->
+<components version="0.16" origin="flatpak">
+	<component type="desktop-application">
+		<id>org.flatpak.Test</id>
+		<name xml:lang="de">Test &amp; mehr</name>
+		<custom>
+			<value key="flathub::verification::verified">true</value>
+			<value key="x-other">kept</value>
+			<value key="flathub::verification::method">website</value>
+			<value key="flathub::verification::login_is_organization">false</value>
+		</custom>
+	</component>
+</components>
+"#
+        );
+
+        // Rewriting again doesn't change anything
+        assert_eq!(
+            rewrite_appstream_xml(
+                &Branding::default(),
+                &storefront_info,
+                "app/org.flatpak.Test/x86_64/stable",
+                &None,
+                &result,
+            )
+            .unwrap(),
+            result
+        );
+    }
+
     #[test]
     fn test_metadata_tag_migration() {
         let input1 = r#"<?xml version="1.0" encoding="utf-8"?>
@@ -684,6 +1749,7 @@ mod tests {
 </components>"#;
 
         let out1 = rewrite_appstream_xml(
+            &Branding::default(),
             &StorefrontInfo::default(),
             "app/org.flatpak.Test/x86_64/stable",
             &None,
@@ -708,6 +1774,7 @@ mod tests {
 </components>"#;
 
         let out2 = rewrite_appstream_xml(
+            &Branding::default(),
             &StorefrontInfo::default(),
             "app/org.flatpak.Test/x86_64/stable",
             &None,
@@ -745,6 +1812,7 @@ mod tests {
 </components>"#;
 
         let out3 = rewrite_appstream_xml(
+            &Branding::default(),
             &StorefrontInfo::default(),
             "app/org.flatpak.Test/x86_64/stable",
             &None,
@@ -775,6 +1843,7 @@ mod tests {
 </components>"#;
 
         let out4 = rewrite_appstream_xml(
+            &Branding::default(),
             &StorefrontInfo::default(),
             "app/org.flatpak.Test/x86_64/stable",
             &None,
@@ -788,4 +1857,143 @@ mod tests {
         assert!(out4.contains(r#"key="flathub::manifest""#));
         assert!(out4.contains("https://example.com/manifest"));
     }
+
+    #[test]
+    fn test_rewrite_appstream_xml_custom_branding() {
+        let original_appstream = r#"<?xml version="1.0" encoding="utf-8"?>
+<components>
+    <component>
+        <id>org.flatpak.Test</id>
+        <url type="bugtracker">https://example.com/issues</url>
+        <custom>
+            <value key="flathub::pricing::minimum_payment">5</value>
+            <value key="example::pricing::minimum_payment">5</value>
+        </custom>
+    </component>
+</components>"#;
+
+        let branding = Branding {
+            namespace: "example".to_string(),
+            bugtracker_url: None,
+            ..Default::default()
+        };
+        let storefront_info = StorefrontInfo {
+            verification: None,
+            pricing: Some(PricingInfo {
+                recommended_donation: Some(1),
+                minimum_payment: None,
+            }),
+            is_free_software: None,
+            lifecycle: None,
+        };
+
+        let result = rewrite_appstream_xml(
+            &branding,
+            &storefront_info,
+            "app/org.flatpak.Test/x86_64/stable",
+            &None,
+            original_appstream,
+        )
+        .unwrap();
+
+        assert_eq!(
+            result,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<components>
+    <component>
+        <id>org.flatpak.Test</id>
+        <url type="bugtracker">https://example.com/issues</url>
+        <custom>
+            <value key="flathub::pricing::minimum_payment">5</value>
+            <value key="example::pricing::recommended_donation">1</value>
+        </custom>
+    </component>
+</components>"#
+        );
+    }
+
+    #[test]
+    fn test_verify_appstream() {
+        let original_appstream = r#"<?xml version="1.0" encoding="utf-8"?>
+<components>
+    <component>
+        <id>org.flatpak.Test</id>
+        <custom>
+            <value key="flathub::manifest">https://example.com/manifest</value>
+        </custom>
+    </component>
+</components>"#;
+
+        let storefront_info = StorefrontInfo {
+            verification: Some(VerificationInfo {
+                verified: true,
+                method: Some(VerificationMethod::Website),
+                ..Default::default()
+            }),
+            pricing: None,
+            is_free_software: None,
+            lifecycle: None,
+        };
+        let refstring = "app/org.flatpak.Test/x86_64/stable";
+
+        let rewritten = rewrite_appstream_xml(
+            &Branding::default(),
+            &storefront_info,
+            refstring,
+            &None,
+            original_appstream,
+        )
+        .unwrap();
+        let appstream = Element::from_reader(rewritten.as_bytes()).unwrap();
+        verify_appstream(
+            &Branding::default(),
+            &storefront_info,
+            &None,
+            refstring,
+            &appstream,
+        )
+        .unwrap();
+
+        // The original doesn't have the verification keys yet
+        let appstream = Element::from_reader(original_appstream.as_bytes()).unwrap();
+        assert!(verify_appstream(
+            &Branding::default(),
+            &storefront_info,
+            &None,
+            refstring,
+            &appstream,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_appstream_values_login_is_organization() {
+        let value = |login_is_organization| {
+            let storefront_info = StorefrontInfo {
+                verification: Some(VerificationInfo {
+                    verified: true,
+                    timestamp: Some(VerificationTimestamp::parse("1672531200").unwrap()),
+                    method: Some(VerificationMethod::LoginProvider),
+                    login_provider: Some("github".to_string()),
+                    login_name: Some("example".to_string()),
+                    login_is_organization,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            appstream_values(
+                &Branding::default(),
+                &storefront_info,
+                &None,
+                "app/org.flatpak.Test/x86_64/stable",
+            )
+            .into_iter()
+            .find(|(key, _)| key == "flathub::verification::login_is_organization")
+            .map(|(_, value)| value)
+        };
+
+        assert_eq!(value(Some(true)).as_deref(), Some("true"));
+        assert_eq!(value(Some(false)).as_deref(), Some("false"));
+        assert_eq!(value(None).as_deref(), Some("false"));
+    }
 }
@@ -3,18 +3,34 @@ use std::{fs, path::PathBuf};
 use anyhow::Result;
 use clap::Args;
 
-use crate::{config::RegularConfig, review::do_review};
+use crate::{
+    config::{FixtureConfig, RegularConfig},
+    review::do_review,
+};
 
 #[derive(Args, Debug)]
 pub struct ReviewArgs {
     /// Path to the config file. The script is usually run in the build directory, so this needs to be an absolute path.
-    #[arg(short, long)]
-    config: PathBuf,
+    #[arg(short, long, required_unless_present = "fixtures")]
+    config: Option<PathBuf>,
+
+    /// Path to a JSON file with recorded answers to use instead of flat-manager and the backend. The calls that
+    /// would have been made are printed as a JSON transcript.
+    #[arg(long, conflicts_with = "config")]
+    fixtures: Option<PathBuf>,
 }
 
 impl ReviewArgs {
     pub fn run(&self) -> Result<()> {
-        let config: RegularConfig = serde_json::from_reader(fs::File::open(self.config.clone())?)?;
+        if let Some(fixtures) = &self.fixtures {
+            let config: FixtureConfig = serde_json::from_reader(fs::File::open(fixtures)?)?;
+            do_review(&config)?;
+            println!("{}", serde_json::to_string_pretty(&*config.transcript())?);
+            return Ok(());
+        }
+
+        let config: RegularConfig =
+            serde_json::from_reader(fs::File::open(self.config.clone().unwrap())?)?;
         do_review(&config)
     }
 }
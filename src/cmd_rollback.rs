@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::{ArgGroup, Args};
+use log::{info, warn};
+use ostree::{
+    gio::{Cancellable, File},
+    Repo,
+};
+
+use crate::{
+    journal::{read_journal, JournalRecord},
+    utils::Transaction,
+};
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("batch").required(true)))]
+pub struct RollbackArgs {
+    /// Path to the journal written by the publish hook.
+    #[arg(short, long)]
+    journal: PathBuf,
+
+    /// Path to the production repo whose refs should be rolled back.
+    #[arg(long, default_value = ".")]
+    repo: PathBuf,
+
+    /// Roll back the refs published from this build.
+    #[arg(long, group = "batch")]
+    build_id: Option<i64>,
+
+    /// Roll back the refs published by the run of the publish hook with this timestamp, as recorded in the journal.
+    /// Use this for republishes, which have no build ID.
+    #[arg(long, group = "batch")]
+    timestamp: Option<String>,
+}
+
+impl RollbackArgs {
+    fn selects(&self, record: &JournalRecord) -> bool {
+        match (&self.build_id, &self.timestamp) {
+            (Some(build_id), _) => record.batch.build_id == Some(*build_id),
+            (_, Some(timestamp)) => &record.batch.timestamp == timestamp,
+            (None, None) => false,
+        }
+    }
+
+    /// Points every ref published in the selected batch back to the commit it had in the production repo before that
+    /// publish. Nothing is changed if any of the refs has been published again since.
+    pub fn run(&self) -> Result<()> {
+        let records: Vec<_> = read_journal(&self.journal)?
+            .into_iter()
+            .filter(|record| self.selects(record))
+            .collect();
+
+        if records.is_empty() {
+            return Err(anyhow!("No journal records match the given batch"));
+        }
+
+        let repo = Repo::new(&File::for_path(&self.repo));
+        repo.open(Cancellable::NONE)?;
+
+        let tx = Transaction::new(&repo)?;
+        let mut moved = vec![];
+        let mut unknown = vec![];
+
+        for record in &records {
+            let Some(current) = repo.resolve_rev(&record.refstring, true)? else {
+                moved.push(record.refstring.clone());
+                continue;
+            };
+            let current_commit = repo.load_commit(&current)?.0;
+
+            // flat-manager keeps the tree of the rewritten commit when it publishes it, so if the ref hasn't moved
+            // since the batch, its current commit has the content we recorded
+            let content_checksum =
+                ostree::commit_get_content_checksum(&current_commit).map(|x| x.to_string());
+            if content_checksum.as_ref() != Some(&record.new_content_checksum) {
+                moved.push(record.refstring.clone());
+                continue;
+            }
+
+            // The published commit is committed on top of the previous one. That's what to go back to when the
+            // publish couldn't record the previous commit itself, e.g. for republishes.
+            let parent = ostree::commit_get_parent(&current_commit).map(|x| x.to_string());
+            let target = match (&record.production_checksum, parent) {
+                (Some(recorded), Some(parent)) if *recorded == parent => parent,
+                (Some(_), _) => {
+                    moved.push(record.refstring.clone());
+                    continue;
+                }
+                (None, Some(parent)) => parent,
+                (None, None) => {
+                    unknown.push(record.refstring.clone());
+                    continue;
+                }
+            };
+
+            // Make sure the original commit still exists before pointing the ref at it
+            repo.load_commit(&target).map_err(|e| {
+                anyhow!("Can't roll back {} to {}: {}", record.refstring, target, e)
+            })?;
+
+            info!(
+                "Rolling back {} from {} to {}",
+                record.refstring, current, target
+            );
+            repo.transaction_set_ref(None, &record.refstring, Some(&target));
+        }
+
+        // Dropping the transaction aborts it, so on any of these errors none of the refs are changed
+        if !moved.is_empty() {
+            return Err(anyhow!(
+                "Refusing to roll back, since these refs have moved since the batch was published: {}",
+                moved.join(", ")
+            ));
+        }
+        if !unknown.is_empty() {
+            return Err(anyhow!(
+                "Refusing to roll back, since there's no previous commit to go back to for: {}",
+                unknown.join(", ")
+            ));
+        }
+
+        tx.commit()?;
+
+        warn!(
+            "The summary and appstream branch of {} still list the published commits. Regenerate them (e.g. with \
+            flat-manager's update-repo job) so clients see the rolled back refs.",
+            self.repo.display()
+        );
+
+        Ok(())
+    }
+}
@@ -1,18 +1,101 @@
-use anyhow::{Ok, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Ok, Result};
 use clap::Args;
+use ostree::{
+    gio::{Cancellable, File},
+    Repo, RepoMode,
+};
+use tempfile::TempDir;
 
 use crate::{
-    config::ValidateConfig,
+    config::{FixtureConfig, ValidateConfig},
     job_utils::{Build, BuildExtended},
     review::do_validation,
+    utils::BuildSource,
 };
 
 #[derive(Args, Debug)]
-pub struct ValidateArgs {}
+pub struct ValidateArgs {
+    /// Path to a JSON file with recorded storefront info, FOSS status, and build info to validate against, instead
+    /// of the offline defaults.
+    #[arg(long)]
+    fixtures: Option<PathBuf>,
+
+    /// Path to a single-file bundle (made with `flatpak build-bundle`) to validate, instead of the repo in the current
+    /// directory.
+    #[arg(long, conflicts_with = "build_dir")]
+    bundle: Option<PathBuf>,
+
+    /// Path to an unexported flatpak-builder build directory to validate, instead of the repo in the current
+    /// directory.
+    #[arg(long)]
+    build_dir: Option<PathBuf>,
+
+    /// The arch of the build directory's ref. Only used with `--build-dir`.
+    #[arg(long, default_value = std::env::consts::ARCH, requires = "build_dir")]
+    arch: String,
+
+    /// The branch of the build directory's ref. Only used with `--build-dir`.
+    #[arg(long, default_value = "master", requires = "build_dir")]
+    branch: String,
+
+    /// Directory to cache flatpak-builder-lint results in, keyed by the checksums of every ref in the repo.
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Maximum number of refs to validate at the same time.
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// JSON file listing the runtimes available in each repo.
+    #[arg(long)]
+    runtime_catalog: Option<PathBuf>,
+}
 
 impl ValidateArgs {
     pub fn run(&self) -> Result<()> {
-        let (_repo, _refs, result) = do_validation(self)?;
+        let fixtures = match &self.fixtures {
+            Some(fixtures) => {
+                let mut config: FixtureConfig = serde_json::from_reader(fs::File::open(fixtures)?)?;
+                if self.cache_dir.is_some() {
+                    config.validation_cache_dir = self.cache_dir.clone();
+                }
+                if let Some(jobs) = self.jobs {
+                    config.validation_threads = jobs;
+                }
+                if self.runtime_catalog.is_some() {
+                    config.runtime_catalog = self.runtime_catalog.clone();
+                }
+                Some(config)
+            }
+            None => None,
+        };
+
+        let bundle_repo = match &self.bundle {
+            Some(bundle) => Some(import_bundle(bundle)?),
+            None => None,
+        };
+
+        let source = match &self.build_dir {
+            Some(build_dir) => {
+                BuildSource::open_build_dir(build_dir.clone(), &self.arch, &self.branch)?
+            }
+            None => match &bundle_repo {
+                Some(dir) => BuildSource::open_repo(dir.path())?,
+                None => BuildSource::open_repo(".")?,
+            },
+        };
+
+        let result = if let Some(config) = &fixtures {
+            do_validation(config, &source)?
+        } else {
+            do_validation(self, &source)?
+        };
 
         /* Print the results */
         println!("{}", serde_json::to_string_pretty(&result)?);
@@ -21,6 +104,32 @@ impl ValidateArgs {
     }
 }
 
+/// Imports a single-file bundle into a new bare-user repo in a temporary directory. The repo is deleted when the
+/// returned `TempDir` is dropped.
+fn import_bundle(bundle: &Path) -> Result<TempDir> {
+    let bundle = bundle.canonicalize()?;
+    let dir = tempfile::tempdir()?;
+
+    let repo = Repo::new(&File::for_path(dir.path()));
+    repo.create(RepoMode::BareUser, Cancellable::NONE)?;
+
+    let status = Command::new("flatpak")
+        .args(["build-import-bundle", "--no-update-summary"])
+        .arg(dir.path())
+        .arg(&bundle)
+        .status()?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "Failed to import bundle {}: flatpak exited with {}",
+            bundle.display(),
+            status
+        ));
+    }
+
+    Ok(dir)
+}
+
 impl ValidateConfig for ValidateArgs {
     fn get_is_free_software(&self, _app_id: &str, _license: Option<&str>) -> Result<bool> {
         Ok(false)
@@ -36,4 +145,16 @@ impl ValidateConfig for ValidateArgs {
             build_refs: vec![],
         })
     }
+
+    fn validation_cache_dir(&self) -> Option<PathBuf> {
+        self.cache_dir.clone()
+    }
+
+    fn validation_threads(&self) -> usize {
+        self.jobs.unwrap_or(1)
+    }
+
+    fn runtime_catalog(&self) -> Option<PathBuf> {
+        self.runtime_catalog.clone()
+    }
 }
@@ -1,22 +1,72 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{LazyLock, Mutex, MutexGuard},
+    time::Duration,
+};
+
 use anyhow::{anyhow, Context, Result};
-use log::info;
+use log::{info, warn};
 use reqwest::blocking::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    job_utils::{BuildExtended, BuildNotificationRequest, CheckStatus, ReviewRequestArgs},
+    job_utils::{Build, BuildExtended, BuildNotificationRequest, CheckStatus, ReviewRequestArgs},
     review::{
         diagnostics::CheckResult,
         moderation::{ReviewRequest, ReviewRequestResponse},
     },
-    storefront::{get_is_free_software, StorefrontInfo},
-    utils::retry,
+    storefront::{
+        get_is_free_software, is_free_software_cache_key, storefront_info_cache_key,
+        StorefrontCache, StorefrontInfo,
+    },
+    utils::{retry, APPID_SKIPLIST},
 };
 
-/// Services for the validation step.
-pub trait ValidateConfig {
+/// Services for the validation step. Validation runs on several threads, so implementations must be `Sync`.
+pub trait ValidateConfig: Sync {
     fn get_is_free_software(&self, app_id: &str, license: Option<&str>) -> Result<bool>;
     fn get_build(&self) -> Result<BuildExtended>;
+
+    /// Gets the backend's storefront info for an app. Without a backend, every app gets the default (empty) info,
+    /// so the validations that need verification data don't report anything.
+    fn get_storefront_info(&self, _app_id: &str) -> Result<StorefrontInfo> {
+        Ok(StorefrontInfo::default())
+    }
+
+    /// Gets the storefront info for several apps at once. By default, this fetches each app separately.
+    fn get_storefront_infos(&self, app_ids: &[String]) -> Result<HashMap<String, StorefrontInfo>> {
+        app_ids
+            .iter()
+            .map(|app_id| Ok((app_id.clone(), self.get_storefront_info(app_id)?)))
+            .collect()
+    }
+
+    fn branding(&self) -> &Branding {
+        static DEFAULT_BRANDING: LazyLock<Branding> = LazyLock::new(Branding::default);
+        &DEFAULT_BRANDING
+    }
+
+    /// Directory to cache validation results in, so unchanged builds aren't linted again. The cache only uses its own
+    /// subdirectory of it, so it can be shared with other programs.
+    fn validation_cache_dir(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Maximum number of refs to validate at the same time.
+    fn validation_threads(&self) -> usize {
+        1
+    }
+
+    /// JSON file listing the runtimes available in each repo, to check apps' runtimes against.
+    fn runtime_catalog(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// The branches refs may have when they're uploaded to the given repo, or `None` if any branch is allowed.
+    fn allowed_branches(&self, _repo: &str) -> Option<&[String]> {
+        None
+    }
 }
 
 pub trait Config: ValidateConfig {
@@ -25,7 +75,8 @@ pub trait Config: ValidateConfig {
     fn get_is_republish(&self) -> Result<bool>;
     fn validation_observe_only(&self) -> bool;
 
-    fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo>;
+    /// Gets a build other than the current one, such as the one a republished commit originally came from.
+    fn get_build_by_id(&self, build_id: i64) -> Result<BuildExtended>;
 
     fn set_check_status(&self, args: &ReviewRequestArgs) -> Result<()>;
 
@@ -66,19 +117,251 @@ pub struct RegularConfig {
     pub flat_manager_token: String,
     #[serde(default)]
     pub validation_observe_only: bool,
+    #[serde(default)]
+    pub validation_cache_dir: Option<PathBuf>,
+    #[serde(default = "default_validation_threads")]
+    pub validation_threads: usize,
+    /// File to append a record of every ref rewritten by the publish hook to, for `rollback`.
+    #[serde(default)]
+    pub rewrite_journal: Option<PathBuf>,
+    /// Paths of the production repos, by name, so the journal can record what each ref pointed to there before a
+    /// publish.
+    #[serde(default)]
+    pub production_repos: HashMap<String, PathBuf>,
+    #[serde(default)]
+    pub branding: Branding,
+    /// Detached metadata keys to copy onto rewritten commits. The GPG signature is always dropped.
+    #[serde(default)]
+    pub preserved_detached_metadata: Vec<String>,
+    /// Keys to check the signatures of uploaded commits against before they're rewritten at publish. If unset,
+    /// signatures aren't checked.
+    #[serde(default)]
+    pub upload_signature: Option<UploadSignatureConfig>,
+    /// Directory to cache the backend's storefront info in. If the backend can't be reached, cached answers are used
+    /// no matter how old they are.
+    #[serde(default)]
+    pub storefront_cache_dir: Option<PathBuf>,
+    /// How long cached storefront info is used without asking the backend, in seconds.
+    #[serde(default = "default_storefront_cache_ttl")]
+    pub storefront_cache_ttl: u64,
+    /// Branches allowed in each repo, like `{"stable": ["stable"], "beta": ["beta", "test"]}`. Entries can use `*`
+    /// wildcards. Repos that aren't listed accept any branch.
+    #[serde(default)]
+    pub branch_rules: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub runtime_catalog: Option<PathBuf>,
+    /// Rules for the `xa.subsets` set on each commit at publish.
+    #[serde(default = "default_subsets")]
+    pub subsets: Vec<SubsetRule>,
+}
+
+fn default_validation_threads() -> usize {
+    1
+}
+
+fn default_storefront_cache_ttl() -> u64 {
+    3600
+}
+
+/// How uploaded commits must be signed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UploadSignatureConfig {
+    /// A GPG signature by a key in the given keyring file.
+    Gpg { keyring: PathBuf },
+    /// An `ostree sign` ed25519 signature by one of the public keys in the given file (base64, one per line).
+    Ed25519 { public_keys: PathBuf },
+}
+
+/// The names and URLs the hooks write into builds. The defaults are Flathub's; other repos can override them. When a
+/// config sets `branding`, every field has to be given, so none of Flathub's values are used by accident.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Branding {
+    /// Prefix of the custom appstream keys, e.g. `flathub` for `flathub::verification::verified`.
+    pub namespace: String,
+    /// Bugtracker URL to set for unverified apps, with `{app_id}` replaced by the app ID. If `null`, the bugtracker
+    /// URL is left as it is.
+    #[serde(deserialize_with = "Option::deserialize")]
+    pub bugtracker_url: Option<String>,
+    /// Apps that aren't validated and aren't expected to have appstream data.
+    pub appid_skiplist: Vec<String>,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Self {
+            namespace: "flathub".to_string(),
+            bugtracker_url: Some("https://github.com/flathub/{app_id}/issues".to_string()),
+            appid_skiplist: APPID_SKIPLIST.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+impl Branding {
+    pub fn bugtracker_url(&self, app_id: &str) -> Option<String> {
+        self.bugtracker_url
+            .as_ref()
+            .map(|template| template.replace("{app_id}", app_id))
+    }
+}
+
+/// A subset to add to commits that match all of the given conditions. Conditions that aren't set match anything.
+/// List conditions match if any of their entries do, and the app ID and runtime ones take `*` wildcards.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SubsetRule {
+    pub name: String,
+    pub verified: Option<bool>,
+    pub free_software: Option<bool>,
+    pub paid: Option<bool>,
+    pub app_ids: Vec<String>,
+    pub arches: Vec<String>,
+    pub branches: Vec<String>,
+    /// Runtime IDs, like `org.gnome.Platform`, without the arch or branch.
+    pub runtimes: Vec<String>,
+}
+
+pub fn default_subsets() -> Vec<SubsetRule> {
+    vec![
+        SubsetRule {
+            name: "verified".to_string(),
+            verified: Some(true),
+            ..Default::default()
+        },
+        SubsetRule {
+            name: "floss".to_string(),
+            free_software: Some(true),
+            ..Default::default()
+        },
+        SubsetRule {
+            name: "verified_floss".to_string(),
+            verified: Some(true),
+            free_software: Some(true),
+            ..Default::default()
+        },
+    ]
 }
 
-impl RegularConfig {}
+impl RegularConfig {
+    fn storefront_cache(&self) -> Option<StorefrontCache> {
+        self.storefront_cache_dir.as_ref().map(|dir| {
+            StorefrontCache::new(dir.clone(), Duration::from_secs(self.storefront_cache_ttl))
+        })
+    }
+
+    /// Fetches storefront info from the backend, using the batch endpoint if it's available.
+    fn fetch_storefront_infos(
+        &self,
+        app_ids: &[String],
+    ) -> Result<HashMap<String, StorefrontInfo>> {
+        if let Some(infos) = StorefrontInfo::fetch_batch(&self.backend_url, app_ids)? {
+            return Ok(infos);
+        }
+
+        app_ids
+            .iter()
+            .map(|app_id| {
+                Ok((
+                    app_id.clone(),
+                    StorefrontInfo::fetch(&self.backend_url, app_id)?,
+                ))
+            })
+            .collect()
+    }
+}
 
 impl ValidateConfig for RegularConfig {
     /// Uses a backend endpoint to determine if an app is FOSS based on its ID and license.
     fn get_is_free_software(&self, app_id: &str, license: Option<&str>) -> Result<bool> {
-        get_is_free_software(&self.backend_url, app_id, license)
+        let fetch = || get_is_free_software(&self.backend_url, app_id, license);
+        match self.storefront_cache() {
+            Some(cache) => cache.get_or_fetch(&is_free_software_cache_key(app_id, license), fetch),
+            None => fetch(),
+        }
     }
 
     fn get_build(&self) -> Result<BuildExtended> {
+        self.get_build_by_id(self.get_build_id()?)
+    }
+
+    fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo> {
+        let fetch = || StorefrontInfo::fetch(&self.backend_url, app_id);
+        match self.storefront_cache() {
+            Some(cache) => cache.get_or_fetch(&storefront_info_cache_key(app_id), fetch),
+            None => fetch(),
+        }
+    }
+
+    fn get_storefront_infos(&self, app_ids: &[String]) -> Result<HashMap<String, StorefrontInfo>> {
+        let Some(cache) = self.storefront_cache() else {
+            return self.fetch_storefront_infos(app_ids);
+        };
+
+        let mut infos = HashMap::new();
+        let mut missing = vec![];
+        for app_id in app_ids {
+            match cache.get_fresh(&storefront_info_cache_key(app_id))? {
+                Some(info) => {
+                    infos.insert(app_id.clone(), info);
+                }
+                None => missing.push(app_id.clone()),
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(infos);
+        }
+
+        match self.fetch_storefront_infos(&missing) {
+            Ok(fetched) => {
+                for (app_id, info) in fetched {
+                    cache.put(&storefront_info_cache_key(&app_id), &info);
+                    infos.insert(app_id, info);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to fetch storefront info, falling back to the cache: {e}");
+                for app_id in missing {
+                    match cache.get_stale(&storefront_info_cache_key(&app_id))? {
+                        Some(info) => {
+                            infos.insert(app_id, info);
+                        }
+                        None => {
+                            return Err(e.context(format!("No cached storefront info for {app_id}")))
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(infos)
+    }
+
+    fn branding(&self) -> &Branding {
+        &self.branding
+    }
+
+    fn validation_cache_dir(&self) -> Option<PathBuf> {
+        self.validation_cache_dir.clone()
+    }
+
+    fn validation_threads(&self) -> usize {
+        self.validation_threads
+    }
+
+    fn allowed_branches(&self, repo: &str) -> Option<&[String]> {
+        self.branch_rules.get(repo).map(Vec::as_slice)
+    }
+
+    fn runtime_catalog(&self) -> Option<PathBuf> {
+        self.runtime_catalog.clone()
+    }
+}
+
+impl Config for RegularConfig {
+    fn get_build_by_id(&self, build_id: i64) -> Result<BuildExtended> {
         let client = Client::new();
-        let build_id = self.get_build_id()?;
         let build = retry(|| {
             client
                 .get(format!(
@@ -92,9 +375,7 @@ impl ValidateConfig for RegularConfig {
         })?;
         Ok(build)
     }
-}
 
-impl Config for RegularConfig {
     fn get_job_id(&self) -> Result<i64> {
         std::env::var("FLAT_MANAGER_JOB_ID")
             .context("Missing environment variable: FLAT_MANAGER_JOB_ID")?
@@ -119,10 +400,6 @@ impl Config for RegularConfig {
         self.validation_observe_only
     }
 
-    fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo> {
-        StorefrontInfo::fetch(&self.backend_url, app_id)
-    }
-
     fn set_check_status(&self, args: &ReviewRequestArgs) -> Result<()> {
         let client = Client::new();
         retry(|| {
@@ -200,3 +477,243 @@ impl Config for RegularConfig {
         Ok(())
     }
 }
+
+/// A config backed by a JSON file of recorded answers instead of live services. Every call that would have been sent
+/// to flat-manager or the backend is appended to a transcript instead.
+#[derive(Deserialize)]
+pub struct FixtureConfig {
+    #[serde(default)]
+    pub build_id: i64,
+    #[serde(default)]
+    pub job_id: i64,
+    #[serde(default)]
+    pub is_republish: bool,
+    #[serde(default)]
+    pub validation_observe_only: bool,
+    #[serde(default = "default_fixture_build")]
+    pub build: BuildExtended,
+    /// Storefront info by app ID. Apps that aren't listed get the default (empty) storefront info.
+    #[serde(default)]
+    pub storefront_info: HashMap<String, StorefrontInfo>,
+    /// FOSS status by app ID. Apps that aren't listed fall back to `is_free_software` in their storefront info.
+    #[serde(default)]
+    pub is_free_software: HashMap<String, bool>,
+    /// The backend's answer to the moderation review request.
+    #[serde(default)]
+    pub requires_review: bool,
+    #[serde(default)]
+    pub validation_cache_dir: Option<PathBuf>,
+    #[serde(default = "default_validation_threads")]
+    pub validation_threads: usize,
+    #[serde(default)]
+    pub branding: Branding,
+    #[serde(default)]
+    pub branch_rules: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub runtime_catalog: Option<PathBuf>,
+    #[serde(skip)]
+    transcript: Mutex<Vec<TranscriptEntry>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "call", content = "args", rename_all = "snake_case")]
+pub enum TranscriptEntry {
+    SetCheckStatus(serde_json::Value),
+    PostReviewRequest(serde_json::Value),
+    PostEmailNotification(serde_json::Value),
+}
+
+fn default_fixture_build() -> BuildExtended {
+    BuildExtended {
+        build: Build {
+            app_id: None,
+            repo: String::from("stable"),
+            build_log_url: None,
+        },
+        build_refs: vec![],
+    }
+}
+
+impl FixtureConfig {
+    pub fn transcript(&self) -> MutexGuard<'_, Vec<TranscriptEntry>> {
+        self.transcript.lock().unwrap()
+    }
+
+    fn record(&self, entry: TranscriptEntry) {
+        self.transcript.lock().unwrap().push(entry);
+    }
+}
+
+impl ValidateConfig for FixtureConfig {
+    fn get_is_free_software(&self, app_id: &str, _license: Option<&str>) -> Result<bool> {
+        Ok(self
+            .is_free_software
+            .get(app_id)
+            .copied()
+            .unwrap_or_else(|| {
+                self.storefront_info
+                    .get(app_id)
+                    .and_then(|info| info.is_free_software)
+                    .unwrap_or(false)
+            }))
+    }
+
+    fn get_build(&self) -> Result<BuildExtended> {
+        Ok(self.build.clone())
+    }
+
+    fn get_storefront_info(&self, app_id: &str) -> Result<StorefrontInfo> {
+        Ok(self
+            .storefront_info
+            .get(app_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn branding(&self) -> &Branding {
+        &self.branding
+    }
+
+    fn validation_cache_dir(&self) -> Option<PathBuf> {
+        self.validation_cache_dir.clone()
+    }
+
+    fn validation_threads(&self) -> usize {
+        self.validation_threads
+    }
+
+    fn allowed_branches(&self, repo: &str) -> Option<&[String]> {
+        self.branch_rules.get(repo).map(Vec::as_slice)
+    }
+
+    fn runtime_catalog(&self) -> Option<PathBuf> {
+        self.runtime_catalog.clone()
+    }
+}
+
+impl Config for FixtureConfig {
+    fn get_build_by_id(&self, _build_id: i64) -> Result<BuildExtended> {
+        Ok(self.build.clone())
+    }
+
+    fn get_build_id(&self) -> Result<i64> {
+        Ok(self.build_id)
+    }
+
+    fn get_job_id(&self) -> Result<i64> {
+        Ok(self.job_id)
+    }
+
+    fn get_is_republish(&self) -> Result<bool> {
+        Ok(self.is_republish)
+    }
+
+    fn validation_observe_only(&self) -> bool {
+        self.validation_observe_only
+    }
+
+    fn set_check_status(&self, args: &ReviewRequestArgs) -> Result<()> {
+        self.record(TranscriptEntry::SetCheckStatus(serde_json::to_value(args)?));
+        Ok(())
+    }
+
+    fn post_review_request(&self, request: ReviewRequest) -> Result<ReviewRequestResponse> {
+        self.record(TranscriptEntry::PostReviewRequest(serde_json::to_value(
+            &request,
+        )?));
+        Ok(ReviewRequestResponse {
+            requires_review: self.requires_review,
+        })
+    }
+
+    fn post_email_notification(&self, result: &CheckResult) -> Result<()> {
+        if result.diagnostics.is_empty() {
+            return Ok(());
+        }
+
+        self.record(TranscriptEntry::PostEmailNotification(
+            serde_json::to_value(&result.diagnostics)?,
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture_is_free_software() {
+        let config: FixtureConfig = serde_json::from_str(
+            r#"{
+                "storefront_info": {
+                    "org.flatpak.Foss": { "is_free_software": true },
+                    "org.flatpak.Override": { "is_free_software": true }
+                },
+                "is_free_software": { "org.flatpak.Override": false }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(config
+            .get_is_free_software("org.flatpak.Foss", None)
+            .unwrap());
+        assert!(!config
+            .get_is_free_software("org.flatpak.Override", None)
+            .unwrap());
+        assert!(!config
+            .get_is_free_software("org.flatpak.Unknown", None)
+            .unwrap());
+        assert_eq!(config.get_build().unwrap().build.repo, "stable");
+    }
+
+    #[test]
+    fn test_branding_fields_are_required() {
+        let branding: Branding = serde_json::from_str(
+            r#"{ "namespace": "example", "bugtracker_url": null, "appid_skiplist": [] }"#,
+        )
+        .unwrap();
+        assert_eq!(branding.namespace, "example");
+        assert_eq!(branding.bugtracker_url("org.example.App"), None);
+
+        /* Leaving a field out doesn't fall back to Flathub's value */
+        assert!(serde_json::from_str::<Branding>(r#"{ "namespace": "example" }"#).is_err());
+        assert!(serde_json::from_str::<Branding>(
+            r#"{ "namespace": "example", "appid_skiplist": [] }"#
+        )
+        .is_err());
+
+        /* Leftover fields are rejected rather than ignored */
+        assert!(serde_json::from_str::<Branding>(
+            r#"{ "namespace": "example", "bugtracker_url": null, "appid_skiplist": [], "subsets": [] }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_fixture_transcript() {
+        let config: FixtureConfig = serde_json::from_str(r#"{ "requires_review": true }"#).unwrap();
+
+        let response = config
+            .post_review_request(ReviewRequest {
+                build_id: 1,
+                job_id: 2,
+            })
+            .unwrap();
+        assert!(response.requires_review);
+
+        config
+            .mark_still_pending(&CheckResult {
+                diagnostics: vec![],
+            })
+            .unwrap();
+
+        let transcript = config.transcript();
+        assert_eq!(transcript.len(), 2);
+        assert!(matches!(
+            transcript[0],
+            TranscriptEntry::PostReviewRequest(_)
+        ));
+        assert!(matches!(transcript[1], TranscriptEntry::SetCheckStatus(_)));
+    }
+}
@@ -2,20 +2,20 @@ use serde::{Deserialize, Serialize};
 
 use crate::review::diagnostics::ValidationDiagnostic;
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct BuildExtended {
     pub build: Build,
     pub build_refs: Vec<BuildRef>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct Build {
     pub app_id: Option<String>,
     pub repo: String,
     pub build_log_url: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct BuildRef {
     pub ref_name: String,
     pub build_log_url: Option<String>,
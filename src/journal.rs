@@ -0,0 +1,110 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Identifies one run of the publish hook. Every record written by that run carries the same batch.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JournalBatch {
+    /// The build that was published, or `None` for republishes.
+    pub build_id: Option<i64>,
+    /// When the publish hook ran, in ISO 8601 format.
+    pub timestamp: String,
+}
+
+/// A record of one ref rewritten by the publish hook, so it can be rolled back later. The journal file is in JSON
+/// Lines format, with one record per line, oldest first.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JournalRecord {
+    #[serde(flatten)]
+    pub batch: JournalBatch,
+    pub refstring: String,
+    /// The commit in the build repo before and after the rewrite.
+    pub old_checksum: String,
+    pub new_checksum: String,
+    /// The content checksum (of the root tree only, see `ostree_commit_get_content_checksum`) of the rewritten
+    /// commit. flat-manager keeps the tree when it imports the commit, so this identifies the published commit in the
+    /// production repo too.
+    pub new_content_checksum: String,
+    /// The commit the ref pointed to in the production repo before this publish, or `None` if it isn't known (the
+    /// production repo isn't configured, the ref wasn't published there yet, or it's a republish).
+    pub production_checksum: Option<String>,
+    pub appstream_diff: Option<String>,
+    pub metadata_diff: Option<String>,
+}
+
+/// Appends the given records to the journal file, creating it if necessary.
+pub fn append_journal(path: &Path, records: &[JournalRecord]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open journal {}", path.display()))?;
+
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(record)?);
+        lines.push('\n');
+    }
+
+    // Write everything at once so an interrupted publish doesn't leave a partial batch
+    file.write_all(lines.as_bytes())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Reads all the records in a journal file, oldest first.
+pub fn read_journal(path: &Path) -> Result<Vec<JournalRecord>> {
+    fs::read_to_string(path)
+        .with_context(|| format!("Failed to read journal {}", path.display()))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid record on line {} of the journal", i + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+
+        let record = |old: &str, new: &str| JournalRecord {
+            batch: JournalBatch {
+                build_id: Some(1),
+                timestamp: "2024-01-01T00:00:00Z".to_string(),
+            },
+            refstring: "app/org.flatpak.Test/x86_64/stable".to_string(),
+            old_checksum: old.to_string(),
+            new_checksum: new.to_string(),
+            new_content_checksum: format!("content-{new}"),
+            production_checksum: Some(format!("production-{old}")),
+            appstream_diff: None,
+            metadata_diff: Some("+xa.token-type: 1\n".to_string()),
+        };
+
+        append_journal(&path, &[record("a", "b")]).unwrap();
+        append_journal(&path, &[record("b", "c")]).unwrap();
+
+        let records = read_journal(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].old_checksum, "a");
+        assert_eq!(records[1].new_checksum, "c");
+        assert_eq!(
+            records[1].production_checksum.as_deref(),
+            Some("production-b")
+        );
+    }
+}
@@ -1,16 +1,20 @@
 mod cmd_publish;
 mod cmd_review;
+mod cmd_rollback;
 mod cmd_validate;
 mod config;
 mod job_utils;
+mod journal;
 mod review;
 mod storefront;
 mod utils;
+mod xml_edit;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use cmd_publish::PublishArgs;
 use cmd_review::ReviewArgs;
+use cmd_rollback::RollbackArgs;
 use cmd_validate::ValidateArgs;
 use std::env;
 
@@ -24,6 +28,7 @@ struct Args {
 enum Command {
     Publish(PublishArgs),
     Review(ReviewArgs),
+    Rollback(RollbackArgs),
     Validate(ValidateArgs),
 }
 
@@ -39,6 +44,7 @@ fn main() -> Result<()> {
     match args.command {
         Command::Publish(cmd) => cmd.run(),
         Command::Review(cmd) => cmd.run(),
+        Command::Rollback(cmd) => cmd.run(),
         Command::Validate(cmd) => cmd.run(),
     }
 }
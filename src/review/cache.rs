@@ -0,0 +1,141 @@
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::utils::{hooks_version, write_atomic};
+
+use super::diagnostics::ValidationDiagnostic;
+
+/// The subdirectory of the configured cache directory that the cache owns. Nothing outside it is ever touched.
+const CACHE_SUBDIR: &str = "flathub-hooks-validation";
+
+/// A file written into each version directory, so only directories created by the cache are ever deleted.
+const MARKER_FILE: &str = "CACHEDIR.TAG";
+
+const MARKER_CONTENT: &str = "Signature: 8a477f597d28d172789f06886806bc55\n\
+    # This directory holds cached validation results from flathub-hooks.\n";
+
+/// On-disk cache of validation results, keyed by a validator-specific key, validator ID and validator version. Entries
+/// are stored under a directory named after the hooks version (see `hooks_version`), so deploying a new build of the
+/// hooks invalidates the whole cache.
+pub struct ValidationCache {
+    dir: PathBuf,
+}
+
+impl ValidationCache {
+    /// Opens the cache in the given directory, deleting entries left by other versions of the hooks.
+    pub fn open(base_dir: PathBuf) -> Result<Self> {
+        let version = hooks_version();
+        let base_dir = base_dir.join(CACHE_SUBDIR);
+
+        fs::create_dir_all(&base_dir)?;
+        for entry in fs::read_dir(&base_dir)? {
+            let entry = entry?;
+            if entry.file_name() != version && entry.path().join(MARKER_FILE).is_file() {
+                info!("Removing stale validation cache {}", entry.path().display());
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        let dir = base_dir.join(version);
+        fs::create_dir_all(&dir)?;
+        let marker = dir.join(MARKER_FILE);
+        if !marker.exists() {
+            write_atomic(&marker, MARKER_CONTENT.as_bytes())?;
+        }
+
+        Ok(Self { dir })
+    }
+
+    fn entry_path(&self, key: &str, validator_id: &str, validator_version: u32) -> PathBuf {
+        self.dir
+            .join(format!("{validator_id}-{validator_version}"))
+            .join(format!("{key}.json"))
+    }
+
+    /// Returns the stored results for the validator under the given key, or runs the validator and stores its results.
+    pub fn get_or_run<F: FnOnce() -> Result<Vec<ValidationDiagnostic>>>(
+        &self,
+        key: &str,
+        validator_id: &str,
+        validator_version: u32,
+        f: F,
+    ) -> Result<Vec<ValidationDiagnostic>> {
+        let path = self.entry_path(key, validator_id, validator_version);
+
+        match fs::read(&path) {
+            Ok(content) => match serde_json::from_slice(&content) {
+                Ok(diagnostics) => {
+                    info!("Using cached {validator_id} results for {key}");
+                    return Ok(diagnostics);
+                }
+                Err(e) => warn!("Ignoring corrupt cache entry {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let diagnostics = f()?;
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, &serde_json::to_vec(&diagnostics)?)?;
+
+        Ok(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::review::diagnostics::DiagnosticInfo;
+
+    use super::*;
+
+    #[test]
+    fn test_get_or_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ValidationCache::open(dir.path().to_owned()).unwrap();
+
+        let run = || {
+            Ok(vec![ValidationDiagnostic::new(
+                DiagnosticInfo::MissingBuildLogUrl,
+                Some("app/org.flatpak.Test/x86_64/stable".to_string()),
+            )])
+        };
+        let diagnostics = cache.get_or_run("abc", "test", 1, run).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+
+        /* A second run on the same commit is served from the cache */
+        let diagnostics = cache
+            .get_or_run("abc", "test", 1, || panic!("validator should not run"))
+            .unwrap();
+        assert_eq!(diagnostics.len(), 1);
+
+        /* A new validator version misses the cache */
+        let diagnostics = cache.get_or_run("abc", "test", 2, || Ok(vec![])).unwrap();
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_open_removes_other_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().join(CACHE_SUBDIR);
+        fs::create_dir_all(base_dir.join("0.0.0-old").join("test-1")).unwrap();
+        fs::write(base_dir.join("0.0.0-old").join(MARKER_FILE), MARKER_CONTENT).unwrap();
+        /* An older build with the same package version */
+        let same_package = base_dir.join(env!("CARGO_PKG_VERSION"));
+        fs::create_dir_all(&same_package).unwrap();
+        fs::write(same_package.join(MARKER_FILE), MARKER_CONTENT).unwrap();
+        fs::create_dir_all(base_dir.join("unrelated")).unwrap();
+        fs::create_dir_all(dir.path().join("other-app")).unwrap();
+
+        ValidationCache::open(dir.path().to_owned()).unwrap();
+
+        assert!(!base_dir.join("0.0.0-old").exists());
+        assert!(!same_package.exists());
+        assert!(base_dir.join(hooks_version()).join(MARKER_FILE).is_file());
+        /* Directories the cache didn't create are left alone */
+        assert!(base_dir.join("unrelated").exists());
+        assert!(dir.path().join("other-app").exists());
+    }
+}
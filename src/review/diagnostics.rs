@@ -1,11 +1,11 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize)]
 pub struct CheckResult {
     pub diagnostics: Vec<ValidationDiagnostic>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ValidationDiagnostic {
     pub refstring: Option<String>,
     pub is_warning: bool,
@@ -13,7 +13,7 @@ pub struct ValidationDiagnostic {
     pub info: DiagnosticInfo,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "category", content = "data", rename_all = "snake_case")]
 pub enum DiagnosticInfo {
     /// The appstream file is missing or couldn't be read.
@@ -25,6 +25,33 @@ pub enum DiagnosticInfo {
     },
     /// The app is FOSS, but a URL for the build's CI log was not given or is not a valid URL.
     MissingBuildLogUrl,
+    /// The app was verified by its website, but the homepage in its appstream data is on a different domain.
+    HomepageDoesNotMatchVerifiedWebsite { website: String, homepage: String },
+    /// The app was verified by its website, but its ID doesn't belong to that domain.
+    AppIdDoesNotMatchVerifiedWebsite { website: String, app_id: String },
+    /// The app ID breaks the naming policy.
+    AppIdPolicy { app_id: String, problem: String },
+    /// The app's runtime or SDK (`key`) is end-of-life.
+    RuntimeEndOfLife {
+        key: String,
+        runtime: String,
+        reason: String,
+    },
+    /// The app's runtime or SDK (`key`) isn't available in the repo the build targets.
+    RuntimeUnavailable {
+        key: String,
+        runtime: String,
+        repo: String,
+    },
+    /// The storefront info couldn't be fetched from the backend, so the checks against the app's verification were
+    /// skipped.
+    StorefrontInfoUnavailable { error: String },
+    /// The ref's branch isn't one of the branches allowed in the repo the build targets.
+    WrongBranchForRepo {
+        repo: String,
+        branch: String,
+        allowed: Vec<String>,
+    },
 }
 
 impl ValidationDiagnostic {
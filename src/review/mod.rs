@@ -1,27 +1,20 @@
-use std::collections::HashMap;
-
 use anyhow::Result;
 use log::info;
-use ostree::gio::{Cancellable, File};
-use ostree::Repo;
 
 use crate::config::{Config, ValidateConfig};
 use crate::review::diagnostics::CheckResult;
 use crate::review::moderation::review_build;
 use crate::review::validation::validate_build;
+use crate::utils::BuildSource;
 
+mod cache;
 pub mod diagnostics;
 pub mod moderation;
+mod runtime_catalog;
 mod validation;
 
-pub fn do_validation<C: ValidateConfig>(
-    config: &C,
-) -> Result<(Repo, HashMap<String, String>, CheckResult)> {
-    /* Open the build repo at the current directory */
-    let repo = Repo::new(&File::for_path("."));
-    repo.open(Cancellable::NONE)?;
-
-    let refs = repo.list_refs(None, Cancellable::NONE)?;
+pub fn do_validation<C: ValidateConfig>(config: &C, source: &BuildSource) -> Result<CheckResult> {
+    let refs = source.list_refs()?;
 
     let build = config.get_build()?;
 
@@ -31,13 +24,15 @@ pub fn do_validation<C: ValidateConfig>(
         diagnostics: vec![],
     };
 
-    validate_build(config, &build, &repo, &refs, &mut result)?;
+    validate_build(config, &build, source, &refs, &mut result)?;
 
-    Ok((repo, refs, result))
+    Ok(result)
 }
 
 pub fn do_review<C: Config>(config: &C) -> Result<()> {
-    let (_, _, result) = do_validation(config)?;
+    /* Open the build repo at the current directory */
+    let source = BuildSource::open_repo(".")?;
+    let result = do_validation(config, &source)?;
 
     /* If any errors were found, mark the check as failed */
     if result.diagnostics.iter().any(|d| !d.is_warning) {
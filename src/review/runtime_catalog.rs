@@ -0,0 +1,106 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::utils::glob_match;
+
+/// The runtimes available in each repo, loaded from a JSON file like
+/// `{"stable": {"org.gnome.Platform/x86_64/47": {}, "org.gnome.Platform/*/44": {"end_of_life": "Use 47"}}}`.
+/// Runtime refs can use `*` wildcards. The runtimes are kept sorted, so lookups don't depend on hash order.
+#[derive(Debug, Default, Deserialize)]
+pub struct RuntimeCatalog(HashMap<String, BTreeMap<String, RuntimeInfo>>);
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RuntimeInfo {
+    /// If set, the runtime is end-of-life and this is the reason.
+    pub end_of_life: Option<String>,
+}
+
+/// What the catalog says about a runtime in a repo.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RuntimeStatus<'a> {
+    /// The catalog doesn't list the repo, so nothing is known about its runtimes.
+    UnknownRepo,
+    Unavailable,
+    Available(&'a RuntimeInfo),
+}
+
+impl RuntimeCatalog {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read(path)
+            .with_context(|| format!("Failed to read runtime catalog {}", path.display()))?;
+        serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse runtime catalog {}", path.display()))
+    }
+
+    /// Looks up a runtime ref (`id/arch/branch`, as in the `runtime=` key of an app's metadata) in a repo.
+    pub fn status(&self, repo: &str, runtime: &str) -> RuntimeStatus<'_> {
+        let Some(runtimes) = self.0.get(repo) else {
+            return RuntimeStatus::UnknownRepo;
+        };
+
+        // Prefer an exact match over a wildcard one, and the most specific (longest) of the matching wildcards. Ties go
+        // to the pattern that sorts first.
+        runtimes
+            .get(runtime)
+            .or_else(|| {
+                runtimes
+                    .iter()
+                    .filter(|(pattern, _)| glob_match(pattern, runtime))
+                    .min_by_key(|(pattern, _)| (Reverse(pattern.len()), *pattern))
+                    .map(|(_, info)| info)
+            })
+            .map_or(RuntimeStatus::Unavailable, RuntimeStatus::Available)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let catalog: RuntimeCatalog = serde_json::from_str(
+            r#"{
+                "stable": {
+                    "org.gnome.Platform/x86_64/47": {},
+                    "org.gnome.Platform/*/44": {"end_of_life": "Use 47"},
+                    "org.gnome.Platform/*/*": {},
+                    "org.gnome.*/*/*": {"end_of_life": "Too vague"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            catalog.status("stable", "org.gnome.Platform/x86_64/47"),
+            RuntimeStatus::Available(RuntimeInfo { end_of_life: None })
+        ));
+        assert!(matches!(
+            catalog.status("stable", "org.gnome.Platform/aarch64/44"),
+            RuntimeStatus::Available(RuntimeInfo {
+                end_of_life: Some(_)
+            })
+        ));
+        /* The most specific matching pattern wins, whatever order the catalog lists them in */
+        assert!(matches!(
+            catalog.status("stable", "org.gnome.Platform/aarch64/47"),
+            RuntimeStatus::Available(RuntimeInfo { end_of_life: None })
+        ));
+        assert_eq!(
+            catalog.status("stable", "org.kde.Platform/aarch64/6.8"),
+            RuntimeStatus::Unavailable
+        );
+        assert_eq!(
+            catalog.status("beta", "org.gnome.Platform/x86_64/47"),
+            RuntimeStatus::UnknownRepo
+        );
+    }
+}
@@ -1,75 +1,209 @@
 use std::collections::HashMap;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 use anyhow::Result;
 use elementtree::Element;
-use ostree::gio::Cancellable;
-use ostree::Repo;
+use log::warn;
+use ostree::gio::{prelude::FileExt, File};
+use ostree::glib::{compute_checksum_for_string, Bytes, ChecksumType, KeyFile, KeyFileFlags};
 use reqwest::Url;
 
 use crate::config::ValidateConfig;
 use crate::{
     job_utils::BuildExtended,
-    utils::{app_id_from_ref, get_appstream_path, is_primary_ref, load_appstream},
+    storefront::{StorefrontInfo, VerificationInfo, VerificationMethod},
+    utils::{app_id_from_ref, glob_match, is_primary_ref, load_appstream, read_file, BuildSource},
 };
 
+use super::cache::ValidationCache;
 use super::diagnostics::{CheckResult, DiagnosticInfo, ValidationDiagnostic};
+use super::runtime_catalog::{RuntimeCatalog, RuntimeStatus};
 
-/// Run all of the validations on a build.
+/// Bump this whenever the flatpak-builder-lint invocation or the handling of its output changes, so cached results
+/// are discarded.
+const FLATPAK_BUILDER_LINT_VERSION: u32 = 1;
+
+/// Run all of the validations on a build. Refs are validated concurrently on up to `config.validation_threads()`
+/// threads, but the diagnostics are always reported in ref order.
 pub fn validate_build<C: ValidateConfig>(
     config: &C,
     build: &BuildExtended,
-    repo: &Repo,
+    source: &BuildSource,
     refs: &HashMap<String, String>,
     result: &mut CheckResult,
 ) -> Result<()> {
-    for (refstring, checksum) in refs.iter() {
-        if is_primary_ref(refstring) {
-            result.diagnostics.extend(validate_primary_ref(
-                config, build, repo, refstring, checksum,
-            )?);
+    let cache = config
+        .validation_cache_dir()
+        .map(ValidationCache::open)
+        .transpose()?;
+    let lint_cache_key = match cache {
+        Some(_) => flatpak_builder_lint_cache_key(source, refs),
+        None => None,
+    };
+    let runtime_catalog = config
+        .runtime_catalog()
+        .map(|path| RuntimeCatalog::load(&path))
+        .transpose()?;
+
+    let mut primary_refs: Vec<_> = refs
+        .iter()
+        .filter(|(refstring, _)| is_primary_ref(refstring, &config.branding().appid_skiplist))
+        .collect();
+    primary_refs.sort();
+
+    /* Fetch the storefront info of every app up front, so each app is only requested once. The verification checks
+    can't run without it, but that shouldn't block the build, so a failure is only a warning. */
+    let mut app_ids: Vec<String> = primary_refs
+        .iter()
+        .map(|(refstring, _)| app_id_from_ref(refstring))
+        .collect();
+    app_ids.sort();
+    app_ids.dedup();
+    let storefront_infos = match config.get_storefront_infos(&app_ids) {
+        Ok(storefront_infos) => storefront_infos,
+        Err(e) => {
+            warn!("Failed to fetch storefront info: {e}");
+            result.diagnostics.push(ValidationDiagnostic {
+                info: DiagnosticInfo::StorefrontInfoUnavailable {
+                    error: e.to_string(),
+                },
+                refstring: None,
+                is_warning: true,
+            });
+            HashMap::new()
+        }
+    };
+
+    let context = ValidationContext {
+        source,
+        storefront_infos,
+        default_storefront_info: StorefrontInfo::default(),
+        cache,
+        lint_cache_key,
+        runtime_catalog,
+    };
+
+    let results: Vec<Mutex<Option<Result<Vec<ValidationDiagnostic>>>>> =
+        primary_refs.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..config
+            .validation_threads()
+            .clamp(1, primary_refs.len().max(1))
+        {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((refstring, checksum)) = primary_refs.get(i) else {
+                    break;
+                };
+                let diagnostics =
+                    validate_primary_ref(config, build, &context, refstring, checksum);
+                *results[i].lock().unwrap() = Some(diagnostics);
+            });
         }
+    });
+
+    for diagnostics in results {
+        result
+            .diagnostics
+            .extend(diagnostics.into_inner().unwrap().unwrap()?);
     }
 
     Ok(())
 }
 
+/// State that's set up once per build and shared by the validation of each of its refs.
+pub struct ValidationContext<'a> {
+    source: &'a BuildSource,
+    /// Storefront info by app ID. Apps missing from it (e.g. because the backend couldn't be reached) are validated
+    /// with the default storefront info.
+    storefront_infos: HashMap<String, StorefrontInfo>,
+    default_storefront_info: StorefrontInfo,
+    cache: Option<ValidationCache>,
+    /// See `flatpak_builder_lint_cache_key`.
+    lint_cache_key: Option<String>,
+    runtime_catalog: Option<RuntimeCatalog>,
+}
+
+impl ValidationContext<'_> {
+    fn storefront_info(&self, app_id: &str) -> &StorefrontInfo {
+        self.storefront_infos
+            .get(app_id)
+            .unwrap_or(&self.default_storefront_info)
+    }
+
+    /// The cache for flatpak-builder-lint results and the key to use in it, if they can be cached.
+    fn lint_cache(&self) -> Option<(&ValidationCache, &str)> {
+        self.cache.as_ref().zip(self.lint_cache_key.as_deref())
+    }
+}
+
 /// Run all the validations specific to "primary" refs (app, runtime, or extension).
 pub fn validate_primary_ref<C: ValidateConfig>(
     config: &C,
     build: &BuildExtended,
-    repo: &Repo,
+    context: &ValidationContext,
     refstring: &str,
     checksum: &str,
 ) -> Result<Vec<ValidationDiagnostic>> {
-    let (_, _checksum) = repo.read_commit(checksum, Cancellable::NONE)?;
+    let root = context.source.read_root(checksum)?;
+    let app_id = app_id_from_ref(refstring);
+    let storefront_info = context.storefront_info(&app_id);
 
     let mut diagnostics = vec![];
-    diagnostics.extend(validate_flatpak_build(refstring, build)?);
+
+    diagnostics.extend(validate_branch(config, build, refstring));
+    if let Some(runtime_catalog) = &context.runtime_catalog {
+        diagnostics.extend(validate_runtimes(runtime_catalog, build, &root, refstring)?);
+    }
+    diagnostics.extend(validate_app_id(
+        &app_id,
+        storefront_info.verification.as_ref(),
+        refstring,
+    ));
+    diagnostics.extend(validate_flatpak_build(
+        refstring,
+        build,
+        context.source,
+        context.lint_cache(),
+    )?);
 
     /* Validate the appstream catalog file. This is the one that shows up on the website and in software centers.
     (The other ones are exported to the user's system.) */
     diagnostics.extend(validate_appstream_catalog_file(
-        config, build, repo, checksum, refstring,
+        config,
+        build,
+        storefront_info,
+        &root,
+        refstring,
     )?);
 
     Ok(diagnostics)
 }
 
-fn run_flatpak_builder_lint(refstring: &str, repo: &str) -> Result<Vec<ValidationDiagnostic>> {
-    let output = Command::new("flatpak")
-        .args([
-            "run",
-            "--command=flatpak-builder-lint",
-            "org.flatpak.Builder",
-            "--exceptions",
-            "--exceptions-repo",
-            repo,
-            "repo",
-            "--cwd",
-            "noop",
-        ])
-        .output()?;
+fn run_flatpak_builder_lint(
+    refstring: &str,
+    repo: &str,
+    source: &BuildSource,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let mut command = Command::new("flatpak");
+    command.args([
+        "run",
+        "--command=flatpak-builder-lint",
+        "org.flatpak.Builder",
+        "--exceptions",
+        "--exceptions-repo",
+        repo,
+    ]);
+    match source {
+        BuildSource::Repo(path) => command.current_dir(path).args(["repo", "--cwd", "noop"]),
+        BuildSource::Directory { path, .. } => command.arg("builddir").arg(path),
+    };
+    let output = command.output()?;
 
     if !output.status.success() {
         let stdout = String::from_utf8_lossy(&output.stdout).to_string();
@@ -92,13 +226,69 @@ fn run_flatpak_builder_lint(refstring: &str, repo: &str) -> Result<Vec<Validatio
     }
 }
 
+/// The key flatpak-builder-lint results are cached under: a checksum of every ref in the repo, since the lint checks
+/// the whole repo (including Locale and Debug refs, screenshots and the appstream branch), and of the installed
+/// linter's commit. Returns `None` if the results can't be cached, e.g. for build directories.
+fn flatpak_builder_lint_cache_key(
+    source: &BuildSource,
+    refs: &HashMap<String, String>,
+) -> Option<String> {
+    if !matches!(source, BuildSource::Repo(_)) {
+        return None;
+    }
+
+    let linter_version = match Command::new("flatpak")
+        .args(["info", "--show-commit", "org.flatpak.Builder"])
+        .output()
+    {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => {
+            warn!("Could not determine the flatpak-builder-lint version, not caching its results");
+            return None;
+        }
+    };
+
+    let mut refs: Vec<_> = refs.iter().collect();
+    refs.sort();
+
+    let mut key = format!("linter {linter_version}\n");
+    for (refstring, checksum) in refs {
+        key.push_str(&format!("{refstring} {checksum}\n"));
+    }
+
+    compute_checksum_for_string(ChecksumType::Sha256, key).map(|x| x.to_string())
+}
+
 fn validate_flatpak_build(
     refstring: &str,
     build: &BuildExtended,
+    source: &BuildSource,
+    cache: Option<(&ValidationCache, &str)>,
 ) -> Result<Vec<ValidationDiagnostic>> {
     let mut diagnostics = vec![];
 
-    diagnostics.extend(run_flatpak_builder_lint(refstring, &build.build.repo)?);
+    let lint = || run_flatpak_builder_lint(refstring, &build.build.repo, source);
+
+    /* The lint's exceptions depend on the target repo, so that's part of the validator ID. The lint covers the whole
+    repo, so the cached diagnostics are shared by every primary ref in it and reassigned to this one. */
+    diagnostics.extend(match cache {
+        Some((cache, key)) => cache
+            .get_or_run(
+                key,
+                &format!("flatpak-builder-lint.{}", build.build.repo),
+                FLATPAK_BUILDER_LINT_VERSION,
+                lint,
+            )?
+            .into_iter()
+            .map(|diagnostic| ValidationDiagnostic {
+                refstring: Some(refstring.to_string()),
+                ..diagnostic
+            })
+            .collect(),
+        _ => lint()?,
+    });
 
     Ok(diagnostics)
 }
@@ -106,16 +296,15 @@ fn validate_flatpak_build(
 fn validate_appstream_catalog_file<C: ValidateConfig>(
     config: &C,
     build: &BuildExtended,
-    repo: &Repo,
-    checksum: &str,
+    storefront_info: &StorefrontInfo,
+    root: &File,
     refstring: &str,
 ) -> Result<Vec<ValidationDiagnostic>> {
     let app_id = app_id_from_ref(refstring);
 
     let mut diagnostics = vec![];
 
-    let appstream_path = get_appstream_path(&app_id);
-    let (_appstream_content, appstream) = match load_appstream(repo, &app_id, checksum) {
+    let (appstream_path, _appstream_content, appstream) = match load_appstream(root, &app_id) {
         Ok(x) => x,
         Err(_) => {
             return Ok(diagnostics);
@@ -154,6 +343,12 @@ fn validate_appstream_catalog_file<C: ValidateConfig>(
         &appstream_path,
     )?);
 
+    diagnostics.extend(validate_verified_website(
+        storefront_info,
+        component,
+        refstring,
+    ));
+
     /* For now, we don't run `appstream-util validate` or `appstreamcli validate` on this file, because it sometimes
     produces false positives. */
 
@@ -182,6 +377,310 @@ fn validate_appstream_catalog_file<C: ValidateConfig>(
     Ok(diagnostics)
 }
 
+/// For apps verified by their website, warn if the appstream homepage or the app ID point to a different domain. That
+/// can mean the domain changed hands, or the app was renamed, since it was verified.
+fn validate_verified_website(
+    storefront_info: &StorefrontInfo,
+    component: &Element,
+    refstring: &str,
+) -> Vec<ValidationDiagnostic> {
+    let app_id = app_id_from_ref(refstring);
+
+    let Some(verification) = storefront_info.verification.as_ref().filter(|v| v.verified) else {
+        return vec![];
+    };
+    let (Some(VerificationMethod::Website), Some(website)) =
+        (verification.method.clone(), verification.website.clone())
+    else {
+        return vec![];
+    };
+    let domain = website_domain(&website);
+
+    let mut diagnostics = vec![];
+    let warn = |info| ValidationDiagnostic {
+        info,
+        refstring: Some(refstring.to_string()),
+        is_warning: true,
+    };
+
+    let homepage = component
+        .find_all("url")
+        .find(|url| url.get_attr("type") == Some("homepage"))
+        .map(|url| url.text().trim().to_string());
+    if let Some(homepage) = homepage {
+        let host = Url::parse(&homepage)
+            .ok()
+            .and_then(|url| url.host_str().map(website_domain));
+        if !host.is_some_and(|host| is_same_or_subdomain(&host, &domain)) {
+            diagnostics.push(warn(DiagnosticInfo::HomepageDoesNotMatchVerifiedWebsite {
+                website: website.clone(),
+                homepage,
+            }));
+        }
+    }
+
+    if !app_id_domain(&app_id).is_some_and(|id_domain| is_same_or_subdomain(&id_domain, &domain)) {
+        diagnostics.push(warn(DiagnosticInfo::AppIdDoesNotMatchVerifiedWebsite {
+            website,
+            app_id,
+        }));
+    }
+
+    diagnostics
+}
+
+/// Make sure the ref's branch is allowed in the repo the build is going to, so it doesn't end up in the wrong channel.
+fn validate_branch<C: ValidateConfig>(
+    config: &C,
+    build: &BuildExtended,
+    refstring: &str,
+) -> Option<ValidationDiagnostic> {
+    let allowed = config.allowed_branches(&build.build.repo)?;
+    let branch = refstring.split('/').nth(3).unwrap_or_default();
+
+    if allowed.iter().any(|pattern| glob_match(pattern, branch)) {
+        return None;
+    }
+
+    Some(ValidationDiagnostic::new(
+        DiagnosticInfo::WrongBranchForRepo {
+            repo: build.build.repo.clone(),
+            branch: branch.to_string(),
+            allowed: allowed.to_vec(),
+        },
+        Some(refstring.to_string()),
+    ))
+}
+
+/// Check the app's runtime and SDK against the runtime catalog. Runtimes that are missing from the target repo would
+/// make the app impossible to install, while end-of-life ones only get a warning.
+fn validate_runtimes(
+    runtime_catalog: &RuntimeCatalog,
+    build: &BuildExtended,
+    root: &File,
+    refstring: &str,
+) -> Result<Vec<ValidationDiagnostic>> {
+    let Ok(metadata) = read_file(&root.resolve_relative_path("metadata")) else {
+        return Ok(vec![]);
+    };
+    let keyfile = KeyFile::new();
+    keyfile.load_from_bytes(&Bytes::from_owned(metadata), KeyFileFlags::NONE)?;
+
+    let mut diagnostics = vec![];
+
+    for key in ["runtime", "sdk"] {
+        let Ok(runtime) = keyfile.string("Application", key) else {
+            continue;
+        };
+        let runtime = runtime.to_string();
+
+        match runtime_catalog.status(&build.build.repo, &runtime) {
+            RuntimeStatus::UnknownRepo => {}
+            RuntimeStatus::Unavailable => diagnostics.push(ValidationDiagnostic::new(
+                DiagnosticInfo::RuntimeUnavailable {
+                    key: key.to_string(),
+                    runtime,
+                    repo: build.build.repo.clone(),
+                },
+                Some(refstring.to_string()),
+            )),
+            RuntimeStatus::Available(info) => {
+                if let Some(reason) = &info.end_of_life {
+                    diagnostics.push(ValidationDiagnostic {
+                        info: DiagnosticInfo::RuntimeEndOfLife {
+                            key: key.to_string(),
+                            runtime,
+                            reason: reason.clone(),
+                        },
+                        refstring: Some(refstring.to_string()),
+                        is_warning: true,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(diagnostics)
+}
+
+/// Prefixes that belong to projects with their own infrastructure. Apps can only use them if they're verified by that
+/// project's domain (or manually).
+const RESERVED_APP_ID_PREFIXES: [&str; 2] = ["org.freedesktop.", "org.gnome."];
+
+/// Prefixes of code forges, where the next component of the app ID is the account the app is hosted under, with the
+/// login provider that account belongs to.
+const FORGE_APP_ID_PREFIXES: [(&str, &str); 6] = [
+    ("io.github.", "github"),
+    ("io.gitlab.", "gitlab"),
+    ("page.codeberg.", "codeberg"),
+    ("io.sourceforge.", "sourceforge"),
+    ("com.github.", "github"),
+    ("com.gitlab.", "gitlab"),
+];
+
+const MAX_APP_ID_LENGTH: usize = 255;
+
+/// Check the app ID against the naming policy. These are only warnings, since renaming a published app is a big deal.
+fn validate_app_id(
+    app_id: &str,
+    verification: Option<&VerificationInfo>,
+    refstring: &str,
+) -> Vec<ValidationDiagnostic> {
+    app_id_policy_problems(app_id, verification)
+        .into_iter()
+        .map(|problem| ValidationDiagnostic {
+            info: DiagnosticInfo::AppIdPolicy {
+                app_id: app_id.to_string(),
+                problem,
+            },
+            refstring: Some(refstring.to_string()),
+            is_warning: true,
+        })
+        .collect()
+}
+
+fn app_id_policy_problems(app_id: &str, verification: Option<&VerificationInfo>) -> Vec<String> {
+    let mut problems = vec![];
+    let parts: Vec<&str> = app_id.split('.').collect();
+
+    if app_id.len() > MAX_APP_ID_LENGTH {
+        problems.push(format!("Longer than {MAX_APP_ID_LENGTH} characters"));
+    }
+    if parts.len() < 3 {
+        problems.push("Needs at least 3 components, like com.example.App".to_string());
+    }
+    for (i, part) in parts.iter().enumerate() {
+        /* Like flatpak, only allow dashes in the last component */
+        let is_last = i == parts.len() - 1;
+        if part.is_empty() {
+            problems.push("Has an empty component".to_string());
+        } else if part.starts_with(|c: char| c.is_ascii_digit()) {
+            problems.push(format!("Component {part:?} starts with a digit"));
+        } else if !part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            problems.push(format!(
+                "Component {part:?} contains characters other than letters, digits, underscores and dashes"
+            ));
+        } else if !is_last && part.contains('-') {
+            problems.push(format!(
+                "Component {part:?} contains a dash, which is only allowed in the last component"
+            ));
+        }
+    }
+    if parts
+        .first()
+        .is_some_and(|tld| tld.chars().any(|c| c.is_ascii_uppercase()))
+    {
+        problems.push("Doesn't start with a lowercase reverse-DNS domain".to_string());
+    }
+    if parts
+        .last()
+        .is_some_and(|last| last.eq_ignore_ascii_case("desktop"))
+    {
+        problems.push("Ends in the legacy .desktop suffix".to_string());
+    }
+
+    let verification = verification.filter(|v| v.verified);
+
+    for prefix in RESERVED_APP_ID_PREFIXES {
+        if !app_id.starts_with(prefix) {
+            continue;
+        }
+
+        let domain = prefix
+            .trim_end_matches('.')
+            .split('.')
+            .rev()
+            .collect::<Vec<_>>()
+            .join(".");
+        let verified_by_owner = verification.is_some_and(|v| match &v.method {
+            Some(VerificationMethod::Manual) => true,
+            Some(VerificationMethod::Website) => v
+                .website
+                .as_deref()
+                .is_some_and(|website| is_same_or_subdomain(&website_domain(website), &domain)),
+            _ => false,
+        });
+        if !verified_by_owner {
+            problems.push(format!(
+                "Uses the reserved prefix {prefix}, but isn't verified by {domain}"
+            ));
+        }
+    }
+
+    for (prefix, provider) in FORGE_APP_ID_PREFIXES {
+        let Some(rest) = app_id.strip_prefix(prefix) else {
+            continue;
+        };
+
+        let account = rest
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches('_')
+            .replace('_', "-");
+        let Some(login) =
+            verification.filter(|v| v.method == Some(VerificationMethod::LoginProvider))
+        else {
+            continue;
+        };
+        let login_provider = login.login_provider.as_deref().unwrap_or_default();
+        if !login_provider.eq_ignore_ascii_case(provider) {
+            problems.push(format!(
+                "Is hosted on {provider}, but was verified with a {login_provider} account"
+            ));
+        } else if let Some(login_name) = login.login_name.as_deref() {
+            if !login_name.eq_ignore_ascii_case(&account) {
+                problems.push(format!(
+                    "Is under the {account} account, but was verified by {login_name}"
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Normalizes a website or host name to a bare lowercase domain, without a scheme, path, port or `www.`.
+fn website_domain(website: &str) -> String {
+    let website = website.trim().to_lowercase();
+    let host = website
+        .split_once("://")
+        .map_or(website.as_str(), |(_, rest)| rest);
+    let host = host.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.split(':').next().unwrap_or_default();
+    let host = host.trim_end_matches('.');
+    host.strip_prefix("www.").unwrap_or(host).to_string()
+}
+
+fn is_same_or_subdomain(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+/// The domain an app ID belongs to: every part but the last, reversed, with underscores turned back into dashes
+/// (`com.example_site.App` belongs to `example-site.com`).
+fn app_id_domain(app_id: &str) -> Option<String> {
+    let parts: Vec<&str> = app_id.split('.').collect();
+    if parts.len() < 3 {
+        return None;
+    }
+
+    Some(
+        parts[..parts.len() - 1]
+            .iter()
+            .rev()
+            .map(|part| {
+                part.trim_start_matches('_')
+                    .replace('_', "-")
+                    .to_lowercase()
+            })
+            .collect::<Vec<_>>()
+            .join("."),
+    )
+}
+
 /// Make sure an appstream component has the correct ID.
 fn check_appstream_component_id(component: &Element, refstring: &str) -> Result<(), String> {
     match component.find_all("id").count() {
@@ -218,3 +717,127 @@ fn validate_appstream_component(
 
     Ok(diagnostics)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::FixtureConfig, job_utils::Build};
+
+    use super::*;
+
+    #[test]
+    fn test_website_domain() {
+        assert_eq!(website_domain("example.com"), "example.com");
+        assert_eq!(website_domain("https://www.Example.com/app"), "example.com");
+        assert_eq!(website_domain("http://example.com:8080?x"), "example.com");
+    }
+
+    #[test]
+    fn test_app_id_domain() {
+        assert_eq!(
+            app_id_domain("com.example.App").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            app_id_domain("org.gnome.design.Palette").as_deref(),
+            Some("design.gnome.org")
+        );
+        assert_eq!(
+            app_id_domain("com.example_site.App").as_deref(),
+            Some("example-site.com")
+        );
+        assert_eq!(app_id_domain("io._0xfoo.App").as_deref(), Some("0xfoo.io"));
+        assert_eq!(app_id_domain("App"), None);
+    }
+
+    #[test]
+    fn test_app_id_policy() {
+        assert!(app_id_policy_problems("com.example.App", None).is_empty());
+        assert!(app_id_policy_problems("io.github.example_user.App", None).is_empty());
+        assert!(app_id_policy_problems("com.example.My-App", None).is_empty());
+
+        for bad_id in [
+            "com.App",
+            "com.example.2App",
+            "com.example+site.App",
+            "com.example-site.App",
+            "Com.example.App",
+            "com.example.App.desktop",
+            "com..App",
+        ] {
+            assert_eq!(app_id_policy_problems(bad_id, None).len(), 1, "{bad_id}");
+        }
+        assert!(
+            !app_id_policy_problems(&format!("com.example.{}", "a".repeat(256)), None).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_app_id_policy_verification() {
+        let website = |website: &str| VerificationInfo {
+            verified: true,
+            method: Some(VerificationMethod::Website),
+            website: Some(website.to_string()),
+            ..Default::default()
+        };
+        let login = |login_name: &str| VerificationInfo {
+            verified: true,
+            method: Some(VerificationMethod::LoginProvider),
+            login_provider: Some("github".to_string()),
+            login_name: Some(login_name.to_string()),
+            ..Default::default()
+        };
+
+        assert!(!app_id_policy_problems("org.gnome.App", None).is_empty());
+        assert!(!app_id_policy_problems("org.gnome.App", Some(&website("example.com"))).is_empty());
+        assert!(app_id_policy_problems("org.gnome.App", Some(&website("gnome.org"))).is_empty());
+
+        assert!(
+            app_id_policy_problems("io.github.example_user.App", Some(&login("Example-User")))
+                .is_empty()
+        );
+        assert!(
+            !app_id_policy_problems("io.github.example_user.App", Some(&login("someone")))
+                .is_empty()
+        );
+        /* The account has to be on the forge the app ID names */
+        assert!(!app_id_policy_problems(
+            "io.gitlab.example_user.App",
+            Some(&login("Example-User"))
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_validate_branch() {
+        let config: FixtureConfig = serde_json::from_str(
+            r#"{"branch_rules": {"stable": ["stable"], "beta": ["beta", "test*"]}}"#,
+        )
+        .unwrap();
+        let build = |repo: &str| BuildExtended {
+            build: Build {
+                app_id: None,
+                repo: repo.to_string(),
+                build_log_url: None,
+            },
+            build_refs: vec![],
+        };
+
+        let validate = |repo: &str, refstring: &str| {
+            validate_branch(&config, &build(repo), refstring).is_some()
+        };
+
+        assert!(!validate("stable", "app/org.flatpak.Test/x86_64/stable"));
+        assert!(validate("stable", "app/org.flatpak.Test/x86_64/beta"));
+        assert!(!validate("beta", "app/org.flatpak.Test/x86_64/test-1"));
+        assert!(validate("beta", "app/org.flatpak.Test/x86_64/stable"));
+        assert!(!validate("other", "app/org.flatpak.Test/x86_64/anything"));
+    }
+
+    #[test]
+    fn test_is_same_or_subdomain() {
+        assert!(is_same_or_subdomain("example.com", "example.com"));
+        assert!(is_same_or_subdomain("docs.example.com", "example.com"));
+        assert!(!is_same_or_subdomain("badexample.com", "example.com"));
+        assert!(!is_same_or_subdomain("example.com", "docs.example.com"));
+    }
+}
@@ -1,35 +1,210 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use anyhow::{anyhow, Result};
-use log::info;
-use serde::Deserialize;
+use log::{info, warn};
+use ostree::glib::{compute_checksum_for_string, ChecksumType, DateTime, TimeZone};
+use reqwest::StatusCode;
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
-use crate::utils::retry;
+use crate::utils::{retry, write_atomic};
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct StorefrontInfo {
     pub verification: Option<VerificationInfo>,
     pub pricing: Option<PricingInfo>,
     pub is_free_software: Option<bool>,
+    pub lifecycle: Option<LifecycleInfo>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct VerificationInfo {
     pub verified: bool,
-    pub timestamp: Option<String>,
-    pub method: Option<String>,
+    pub timestamp: Option<VerificationTimestamp>,
+    pub method: Option<VerificationMethod>,
     pub website: Option<String>,
     pub login_provider: Option<String>,
     pub login_name: Option<String>,
     pub login_is_organization: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerificationMethod {
+    None,
+    Manual,
+    Website,
+    LoginProvider,
+    /// A method added to the backend after this version of the hooks. The name is kept, so it's published exactly as
+    /// the backend sent it.
+    Unknown(String),
+}
+
+impl VerificationMethod {
+    pub fn parse(text: &str) -> Self {
+        match text {
+            "none" => VerificationMethod::None,
+            "manual" => VerificationMethod::Manual,
+            "website" => VerificationMethod::Website,
+            "login_provider" => VerificationMethod::LoginProvider,
+            _ => VerificationMethod::Unknown(text.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            VerificationMethod::None => "none",
+            VerificationMethod::Manual => "manual",
+            VerificationMethod::Website => "website",
+            VerificationMethod::LoginProvider => "login_provider",
+            VerificationMethod::Unknown(text) => text,
+        }
+    }
+}
+
+impl Serialize for VerificationMethod {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for VerificationMethod {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::parse(&String::deserialize(deserializer)?))
+    }
+}
+
+/// When an app was verified. The backend sends it as a number of seconds since the Unix epoch, a string of digits, or
+/// an ISO 8601 date (UTC unless it says otherwise). The original text is kept, so it's published exactly as the
+/// backend sent it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerificationTimestamp {
+    /// Seconds since the Unix epoch.
+    pub seconds: i64,
+    text: String,
+}
+
+impl VerificationTimestamp {
+    pub fn parse(text: &str) -> Result<Self> {
+        let seconds = match text.trim().parse() {
+            Ok(seconds) => seconds,
+            Err(_) => DateTime::from_iso8601(text, Some(&TimeZone::utc()))
+                .map_err(|_| anyhow!("Invalid verification timestamp: {text:?}"))?
+                .to_unix(),
+        };
+
+        Ok(Self {
+            seconds,
+            text: text.to_string(),
+        })
+    }
+}
+
+impl Display for VerificationTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Serialize for VerificationTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text)
+    }
+}
+
+impl<'de> Deserialize<'de> for VerificationTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(i64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(seconds) => Ok(Self {
+                seconds,
+                text: seconds.to_string(),
+            }),
+            Raw::Text(text) => Self::parse(&text).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl VerificationInfo {
+    /// Rejects combinations of fields that contradict each other, so they never end up in a published catalog.
+    pub fn check_consistency(&self) -> Result<()> {
+        let method = self.method.as_ref().unwrap_or(&VerificationMethod::None);
+
+        if self.verified && *method == VerificationMethod::None {
+            return Err(anyhow!("App is verified, but has no verification method"));
+        }
+        if !self.verified && *method != VerificationMethod::None {
+            return Err(anyhow!(
+                "App is not verified, but has verification method {}",
+                method.as_str()
+            ));
+        }
+        if self.verified && self.timestamp.is_none() {
+            return Err(anyhow!(
+                "App is verified, but has no verification timestamp"
+            ));
+        }
+        if *method == VerificationMethod::Website && self.website.is_none() {
+            return Err(anyhow!("App was verified by website, but has no website"));
+        }
+        if *method == VerificationMethod::LoginProvider
+            && (self.login_provider.is_none() || self.login_name.is_none())
+        {
+            return Err(anyhow!(
+                "App was verified by login, but is missing the login provider or name"
+            ));
+        }
+        if *method != VerificationMethod::LoginProvider && self.login_is_organization == Some(true)
+        {
+            return Err(anyhow!(
+                "App wasn't verified by login, but has login_is_organization set"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PricingInfo {
     pub recommended_donation: Option<i32>,
     pub minimum_payment: Option<i32>,
 }
 
+/// End-of-life status set by the backend, which is applied to the app's commits at publish.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LifecycleInfo {
+    /// If set, the app is end-of-life and this is the reason shown to users.
+    pub end_of_life: Option<String>,
+    /// If set, the app was renamed to this ID, and clients should switch to it.
+    pub end_of_life_rebase: Option<String>,
+}
+
 impl StorefrontInfo {
+    /// Checks that the info from the backend makes sense before it's applied to a build.
+    pub fn check_consistency(&self) -> Result<()> {
+        if let Some(verification) = &self.verification {
+            verification.check_consistency()?;
+        }
+        Ok(())
+    }
+
     pub fn fetch(backend_url: &str, app_id: &str) -> Result<Self> {
         let endpoint = format!("{backend_url}/purchases/storefront-info");
 
@@ -37,8 +212,8 @@ impl StorefrontInfo {
 
         let client = reqwest::blocking::Client::new();
 
-        // Fetch the storefront info
-        let storefront_info = retry(|| {
+        // Fetch the storefront info. It's parsed outside of the retry, since an invalid response won't get any better.
+        let body = retry(|| {
             let response = client
                 .get(&endpoint)
                 .query(&[("app_id", app_id)])
@@ -47,20 +222,202 @@ impl StorefrontInfo {
 
             if response.status() == 404 {
                 info!("storefront-info endpoint returned 404; this must be a new app");
-                return Ok(StorefrontInfo::default());
+                return Ok(None);
+            }
+
+            response
+                .error_for_status()
+                .map_err(convert_err)?
+                .text()
+                .map(Some)
+                .map_err(convert_err)
+        })?;
+
+        match body {
+            Some(body) => serde_json::from_str(&body)
+                .map_err(|e| anyhow!("Invalid storefront info from {}: {}", &endpoint, e)),
+            None => Ok(StorefrontInfo::default()),
+        }
+    }
+
+    /// Fetches the storefront info for several apps in one request. Apps the backend doesn't know about get the
+    /// default storefront info, like in `fetch`. Returns `None` if the backend doesn't have the batch endpoint.
+    pub fn fetch_batch(
+        backend_url: &str,
+        app_ids: &[String],
+    ) -> Result<Option<HashMap<String, Self>>> {
+        let endpoint = format!("{backend_url}/purchases/storefront-info/batch");
+
+        let convert_err = |e| anyhow!("Failed to fetch storefront info from {}: {}", &endpoint, e);
+
+        let client = reqwest::blocking::Client::new();
+
+        let body = retry(|| {
+            let response = client
+                .post(&endpoint)
+                .json(app_ids)
+                .send()
+                .map_err(convert_err)?;
+
+            if matches!(
+                response.status(),
+                StatusCode::NOT_FOUND
+                    | StatusCode::METHOD_NOT_ALLOWED
+                    | StatusCode::NOT_IMPLEMENTED
+            ) {
+                info!("Batch storefront-info endpoint is not available");
+                return Ok(None);
             }
 
             response
                 .error_for_status()
                 .map_err(convert_err)?
-                .json::<StorefrontInfo>()
+                .text()
+                .map(Some)
                 .map_err(convert_err)
         })?;
 
-        Ok(storefront_info)
+        let Some(body) = body else {
+            return Ok(None);
+        };
+        let mut infos: HashMap<String, StorefrontInfo> = serde_json::from_str(&body)
+            .map_err(|e| anyhow!("Invalid storefront info from {}: {}", &endpoint, e))?;
+
+        Ok(Some(
+            app_ids
+                .iter()
+                .map(|app_id| (app_id.clone(), infos.remove(app_id).unwrap_or_default()))
+                .collect(),
+        ))
+    }
+}
+
+/// On-disk cache of answers from the backend's storefront endpoints. Entries younger than the TTL are used instead of
+/// asking the backend; older ones are only used if the backend can't be reached.
+pub struct StorefrontCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CacheEntry<T> {
+    key: String,
+    fetched_at: u64,
+    value: T,
+}
+
+impl StorefrontCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Self { dir, ttl }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        // Keys contain app IDs and whole SPDX expressions, which can be too long or unsafe for a file name, so name
+        // the file by a hash of the key. The key is stored in the entry too.
+        let filename = compute_checksum_for_string(ChecksumType::Sha256, key).unwrap_or_default();
+        self.dir.join(format!("{filename}.json"))
+    }
+
+    /// Returns the cached value and its age, if there is one.
+    fn read<T: DeserializeOwned>(&self, key: &str) -> Result<Option<(T, Duration)>> {
+        let path = self.entry_path(key);
+
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        match serde_json::from_slice::<CacheEntry<T>>(&content) {
+            Ok(entry) if entry.key == key => {
+                let age = now().saturating_sub(Duration::from_secs(entry.fetched_at));
+                Ok(Some((entry.value, age)))
+            }
+            Ok(_) => Ok(None),
+            Err(e) => {
+                warn!("Ignoring corrupt cache entry {}: {}", path.display(), e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the cached value if it's younger than the TTL.
+    pub fn get_fresh<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self
+            .read(key)?
+            .filter(|(_, age)| *age < self.ttl)
+            .map(|(value, _)| value))
+    }
+
+    /// Returns the cached value no matter how old it is, with a warning if it's past the TTL.
+    pub fn get_stale<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.read(key)?.map(|(value, age)| {
+            if age >= self.ttl {
+                warn!("Using cached {key} from {} seconds ago", age.as_secs());
+            }
+            value
+        }))
+    }
+
+    /// Stores a value. The cache is only an optimization, so failing to write it is logged instead of failing the
+    /// caller.
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) {
+        if let Err(e) = self.write(key, value) {
+            warn!("Failed to cache {key}: {e}");
+        }
+    }
+
+    fn write<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let entry = CacheEntry {
+            key: key.to_string(),
+            fetched_at: now().as_secs(),
+            value,
+        };
+        write_atomic(&self.entry_path(key), &serde_json::to_vec(&entry)?)
+    }
+
+    /// Returns the cached value if it's fresh, or fetches and caches a new one. If fetching fails, a stale cached
+    /// value is used instead.
+    pub fn get_or_fetch<T: Serialize + DeserializeOwned, F: FnOnce() -> Result<T>>(
+        &self,
+        key: &str,
+        fetch: F,
+    ) -> Result<T> {
+        if let Some(value) = self.get_fresh(key)? {
+            return Ok(value);
+        }
+
+        match fetch() {
+            Ok(value) => {
+                self.put(key, &value);
+                Ok(value)
+            }
+            Err(e) => match self.get_stale(key)? {
+                Some(value) => {
+                    warn!("Backend request failed, falling back to the cache: {e}");
+                    Ok(value)
+                }
+                None => Err(e),
+            },
+        }
     }
 }
 
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+pub fn storefront_info_cache_key(app_id: &str) -> String {
+    format!("storefront-info-{app_id}")
+}
+
+pub fn is_free_software_cache_key(app_id: &str, license: Option<&str>) -> String {
+    format!("is-free-software-{app_id}-{}", license.unwrap_or_default())
+}
+
 /// Uses a backend endpoint to determine if an app is FOSS based on its ID and license.
 pub fn get_is_free_software(
     backend_url: &str,
@@ -86,3 +443,150 @@ pub fn get_is_free_software(
             .map_err(Into::into)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_timestamp() {
+        let parse = |json: &str| {
+            serde_json::from_str::<VerificationTimestamp>(json)
+                .ok()
+                .map(|timestamp| (timestamp.seconds, timestamp.to_string()))
+        };
+
+        assert_eq!(
+            parse("1672531200"),
+            Some((1672531200, "1672531200".to_string()))
+        );
+        assert_eq!(
+            parse(r#""1672531200""#),
+            Some((1672531200, "1672531200".to_string()))
+        );
+        assert_eq!(
+            parse(r#""2023-01-01T00:00:00""#),
+            Some((1672531200, "2023-01-01T00:00:00".to_string()))
+        );
+        assert_eq!(
+            parse(r#""2023-01-01T01:00:00+01:00""#),
+            Some((1672531200, "2023-01-01T01:00:00+01:00".to_string()))
+        );
+        assert_eq!(parse(r#""yesterday""#), None);
+
+        /* The original text survives a round trip through the storefront cache */
+        let timestamp = VerificationTimestamp::parse("2023-01-01T00:00:00").unwrap();
+        let json = serde_json::to_string(&timestamp).unwrap();
+        assert_eq!(
+            serde_json::from_str::<VerificationTimestamp>(&json).unwrap(),
+            timestamp
+        );
+    }
+
+    #[test]
+    fn test_unknown_verification_method() {
+        let info: VerificationInfo = serde_json::from_str(
+            r#"{ "verified": true, "method": "carrier_pigeon", "timestamp": "1672531200" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            info.method,
+            Some(VerificationMethod::Unknown("carrier_pigeon".to_string()))
+        );
+        assert!(info.check_consistency().is_ok());
+
+        /* The name survives a round trip through the storefront cache */
+        let json = serde_json::to_string(&info).unwrap();
+        let info: VerificationInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(info.method.unwrap().as_str(), "carrier_pigeon");
+    }
+
+    #[test]
+    fn test_verification_consistency() {
+        let website = VerificationInfo {
+            verified: true,
+            timestamp: Some(VerificationTimestamp::parse("1672531200").unwrap()),
+            method: Some(VerificationMethod::Website),
+            website: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(website.check_consistency().is_ok());
+        /* The backend sends false for apps that weren't verified by login */
+        assert!(VerificationInfo {
+            login_is_organization: Some(false),
+            ..website.clone()
+        }
+        .check_consistency()
+        .is_ok());
+        assert!(VerificationInfo::default().check_consistency().is_ok());
+
+        let inconsistent = [
+            VerificationInfo {
+                website: None,
+                ..website.clone()
+            },
+            VerificationInfo {
+                verified: false,
+                ..website.clone()
+            },
+            VerificationInfo {
+                login_is_organization: Some(true),
+                ..website.clone()
+            },
+            VerificationInfo {
+                method: Some(VerificationMethod::LoginProvider),
+                login_provider: Some("github".to_string()),
+                ..website.clone()
+            },
+            VerificationInfo {
+                timestamp: None,
+                ..website.clone()
+            },
+        ];
+        for info in inconsistent {
+            assert!(info.check_consistency().is_err(), "{info:?}");
+        }
+    }
+
+    #[test]
+    fn test_get_or_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = StorefrontCache::new(dir.path().to_owned(), Duration::from_secs(3600));
+
+        assert_eq!(cache.get_or_fetch("a", || Ok(1)).unwrap(), 1);
+        // Fresh entries are used without asking the backend
+        assert_eq!(
+            cache
+                .get_or_fetch("a", || -> Result<i32> { panic!("should not fetch") })
+                .unwrap(),
+            1
+        );
+        // Entries that were never fetched can't fall back to anything
+        assert!(cache
+            .get_or_fetch("b", || -> Result<i32> { Err(anyhow!("offline")) })
+            .is_err());
+    }
+
+    #[test]
+    fn test_stale_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = StorefrontCache::new(dir.path().to_owned(), Duration::ZERO);
+
+        cache.put("a", &1);
+        assert_eq!(cache.get_fresh::<i32>("a").unwrap(), None);
+
+        // Keys longer than a file name can be are still cached
+        let long_key = format!("is-free-software-org.example.App-{}", "MIT OR ".repeat(50));
+        cache.put(&long_key, &true);
+        assert_eq!(cache.get_stale::<bool>(&long_key).unwrap(), Some(true));
+
+        // Stale entries are refreshed when the backend is up, and used when it's down
+        assert_eq!(cache.get_or_fetch("a", || Ok(2)).unwrap(), 2);
+        assert_eq!(
+            cache
+                .get_or_fetch("a", || -> Result<i32> { Err(anyhow!("offline")) })
+                .unwrap(),
+            2
+        );
+    }
+}
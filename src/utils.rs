@@ -1,18 +1,25 @@
-use std::io::Read;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
 
 use anyhow::{anyhow, Result};
 use elementtree::Element;
-use flate2::read::GzDecoder;
-use log::info;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{info, warn};
 use ostree::{
-    gio::Cancellable,
+    gio::{prelude::FileExtManual, Cancellable, File},
     glib,
-    glib::GString,
+    glib::{GString, KeyFile, KeyFileFlags},
     prelude::{Cast, FileExt, InputStreamExtManual},
     MutableTree, Repo, RepoFile,
 };
 
 pub const APP_SUFFIXES: [&str; 3] = ["Sources", "Debug", "Locale"];
+/// The default skiplist, see `Branding::appid_skiplist`.
 pub const APPID_SKIPLIST: [&str; 9] = [
     "net.wz2100.wz2100",
     "org.freedesktop.Platform.ClInfo",
@@ -37,16 +44,33 @@ pub fn app_id_from_ref(refstring: &str) -> String {
 }
 
 /// Determines whether the refstring is either an app or extension (as opposed to a Sources/Debug/Locales ref, or
-/// something else like the branch we store screenshots in).
-pub fn is_primary_ref(refstring: &str) -> bool {
+/// something else like the branch we store screenshots in). Apps in the skiplist don't count.
+pub fn is_primary_ref(refstring: &str, skiplist: &[String]) -> bool {
     if refstring.starts_with("app/") {
-        let appid = refstring.split('/').nth(1).unwrap().to_string();
-        !APPID_SKIPLIST.contains(&appid.as_str())
+        let appid = refstring.split('/').nth(1).unwrap();
+        !skiplist.iter().any(|x| x == appid)
     } else {
         false
     }
 }
 
+/// Matches text against a pattern where `*` stands for any sequence of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            // Try every possible length for the part the wildcard stands for
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
 pub fn mtree_lookup(
     mtree: &MutableTree,
     path: &[&str],
@@ -88,29 +112,165 @@ pub fn read_repo_file(file: &RepoFile) -> Result<Vec<u8>> {
     read_file_from_repo(&file.repo(), &file.checksum())
 }
 
-pub fn get_appstream_path(app_id: &str) -> String {
-    format!("files/share/app-info/xmls/{app_id}.xml.gz")
+/// Reads a file from either an OSTree commit or the local filesystem.
+pub fn read_file(file: &File) -> Result<Vec<u8>> {
+    if let Some(repo_file) = file.downcast_ref::<RepoFile>() {
+        return read_repo_file(repo_file);
+    }
+
+    if !file.query_exists(Cancellable::NONE) {
+        return Err(anyhow!("File does not exist"));
+    }
+
+    Ok(file.load_contents(Cancellable::NONE)?.0.to_vec())
+}
+
+/// The refs being validated and where to read their files from. This only holds paths, not GObject handles, so it can
+/// be shared between threads; each call opens what it needs.
+pub enum BuildSource {
+    /// An OSTree repo. Each ref points to a commit in the repo.
+    Repo(PathBuf),
+    /// An unexported flatpak-builder build directory (with `files/`, `export/` and `metadata`), which holds the files
+    /// for a single ref.
+    Directory { path: PathBuf, refstring: String },
+}
+
+fn open_repo(path: &Path) -> Result<Repo> {
+    let repo = Repo::new(&File::for_path(path));
+    repo.open(Cancellable::NONE)?;
+    Ok(repo)
+}
+
+impl BuildSource {
+    /// Opens the OSTree repo at the given path.
+    pub fn open_repo(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        open_repo(&path)?;
+        Ok(Self::Repo(path))
+    }
+
+    /// Opens a flatpak-builder build directory. The ref is built from the name in its `metadata` file and the given
+    /// arch and branch, since the build directory doesn't record those.
+    pub fn open_build_dir(path: PathBuf, arch: &str, branch: &str) -> Result<Self> {
+        let keyfile = KeyFile::new();
+        keyfile.load_from_file(path.join("metadata"), KeyFileFlags::NONE)?;
+
+        let (kind, group) = if keyfile.has_group("Application") {
+            ("app", "Application")
+        } else {
+            ("runtime", "Runtime")
+        };
+        let name = keyfile.string(group, "name")?;
+
+        Ok(Self::Directory {
+            path,
+            refstring: format!("{kind}/{name}/{arch}/{branch}"),
+        })
+    }
+
+    /// Lists the refs in the source, mapped to their commit checksums. Build directories aren't committed yet, so
+    /// their single ref maps to an empty checksum.
+    pub fn list_refs(&self) -> Result<HashMap<String, String>> {
+        match self {
+            Self::Repo(path) => Ok(open_repo(path)?.list_refs(None, Cancellable::NONE)?),
+            Self::Directory { refstring, .. } => Ok([(refstring.clone(), String::new())].into()),
+        }
+    }
+
+    /// Gets the root of the file tree for the given commit.
+    pub fn read_root(&self, checksum: &str) -> Result<File> {
+        match self {
+            Self::Repo(path) => Ok(open_repo(path)?.read_commit(checksum, Cancellable::NONE)?.0),
+            Self::Directory { path, .. } => Ok(File::for_path(path)),
+        }
+    }
+}
+
+/// Directories, relative to the root of a commit, where appstream catalogs are found, in order of preference.
+pub const APPSTREAM_CATALOG_DIRS: [&str; 2] =
+    ["files/share/app-info/xmls", "files/share/swcatalog/xml"];
+
+/// The compression used for an appstream catalog file, based on its extension.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CatalogEncoding {
+    Gzip,
+    Plain,
+    Zstd,
 }
 
-/// Loads the appstream file from the given commit. Returns the file contents and the parsed XML.
-pub fn load_appstream(repo: &Repo, app_id: &str, checksum: &str) -> Result<(String, Element)> {
-    let (file, _checksum) = repo.read_commit(checksum, Cancellable::NONE)?;
+impl CatalogEncoding {
+    /// All the supported encodings, in order of preference.
+    pub const ALL: [Self; 3] = [Self::Gzip, Self::Plain, Self::Zstd];
 
-    let appstream_path = get_appstream_path(app_id);
-    let appstream_file = file.resolve_relative_path(&appstream_path);
-    let appstream_content = read_repo_file(appstream_file.downcast_ref().unwrap())?;
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "xml.gz",
+            Self::Plain => "xml",
+            Self::Zstd => "xml.zst",
+        }
+    }
 
-    let content = if appstream_path.ends_with(".gz") {
+    pub fn decode(self, data: &[u8]) -> Result<String> {
         let mut s = String::new();
-        GzDecoder::new(&*appstream_content).read_to_string(&mut s)?;
-        s
-    } else {
-        String::from_utf8(appstream_content)?
-    };
+        match self {
+            Self::Gzip => {
+                GzDecoder::new(data).read_to_string(&mut s)?;
+            }
+            Self::Plain => s = String::from_utf8(data.to_vec())?,
+            Self::Zstd => {
+                zstd::Decoder::new(data)?.read_to_string(&mut s)?;
+            }
+        }
+        Ok(s)
+    }
+
+    pub fn encode(self, content: &str) -> Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(content.as_bytes())?;
+                Ok(encoder.finish()?)
+            }
+            Self::Plain => Ok(content.as_bytes().to_vec()),
+            Self::Zstd => Ok(zstd::encode_all(content.as_bytes(), 0)?),
+        }
+    }
+}
+
+/// Lists the places an app's appstream catalog may be, in order of preference, as (directory, filename, encoding).
+pub fn appstream_catalog_candidates(app_id: &str) -> Vec<(&'static str, String, CatalogEncoding)> {
+    APPSTREAM_CATALOG_DIRS
+        .iter()
+        .flat_map(|dir| {
+            CatalogEncoding::ALL.iter().map(move |encoding| {
+                (
+                    *dir,
+                    format!("{app_id}.{}", encoding.extension()),
+                    *encoding,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Loads the appstream catalog from the given file tree, from the first of the candidate locations that exists.
+/// Returns the path it was found at, the file contents and the parsed XML.
+pub fn load_appstream(root: &File, app_id: &str) -> Result<(String, String, Element)> {
+    let (appstream_path, encoding) = appstream_catalog_candidates(app_id)
+        .into_iter()
+        .map(|(dir, filename, encoding)| (format!("{dir}/{filename}"), encoding))
+        .find(|(path, _)| {
+            root.resolve_relative_path(path)
+                .query_exists(Cancellable::NONE)
+        })
+        .ok_or(anyhow!("File does not exist"))?;
+
+    let appstream_file = root.resolve_relative_path(&appstream_path);
+    let content = encoding.decode(&read_file(&appstream_file)?)?;
 
     let root = Element::from_reader(content.as_bytes())?;
 
-    Ok((content, root))
+    Ok((appstream_path, content, root))
 }
 
 /// Wrapper for OSTree transactions that automatically aborts the transaction when dropped if it hasn't been committed.
@@ -145,6 +305,37 @@ impl Drop for Transaction<'_> {
     }
 }
 
+/// Writes a file by renaming a temporary file into place, so concurrent readers and writers never see a partial file.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = tempfile::NamedTempFile::new_in(path.parent().unwrap())?;
+    file.write_all(content)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+/// Identifies this build of the hooks: the package version plus a hash of the running executable. The package version
+/// alone isn't bumped between deployments, so anything that must change when the hooks are rebuilt (the validation
+/// cache, provenance metadata) uses this instead.
+pub fn hooks_version() -> &'static str {
+    static HOOKS_VERSION: LazyLock<String> = LazyLock::new(|| {
+        let version = env!("CARGO_PKG_VERSION");
+        let exe = std::env::current_exe().and_then(fs::read);
+        match exe {
+            Ok(exe) => {
+                let hash = glib::compute_checksum_for_data(glib::ChecksumType::Sha256, &exe)
+                    .map(|x| x.to_string())
+                    .unwrap_or_default();
+                format!("{version}-{}", &hash[..hash.len().min(16)])
+            }
+            Err(e) => {
+                warn!("Failed to read the hooks executable, identifying it by the package version only: {e}");
+                version.to_string()
+            }
+        }
+    });
+    &HOOKS_VERSION
+}
+
 /// Try the given retry function up to `retry_count + 1` times. The first successful result is returned, or the last error if all attempts failed.
 pub fn retry<T, E: std::fmt::Display, F: Fn() -> Result<T, E>>(f: F) -> Result<T, E> {
     let mut i = 0;
@@ -197,12 +388,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_catalog_encoding_round_trip() {
+        let content = r#"<?xml version="1.0" encoding="utf-8"?><components/>"#;
+        for encoding in CatalogEncoding::ALL {
+            let encoded = encoding.encode(content).unwrap();
+            assert_eq!(encoding.decode(&encoded).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_appstream_catalog_candidates() {
+        let candidates = appstream_catalog_candidates("org.flatpak.Test");
+        assert_eq!(
+            candidates[0],
+            (
+                "files/share/app-info/xmls",
+                "org.flatpak.Test.xml.gz".to_string(),
+                CatalogEncoding::Gzip
+            )
+        );
+        assert!(candidates.contains(&(
+            "files/share/swcatalog/xml",
+            "org.flatpak.Test.xml.zst".to_string(),
+            CatalogEncoding::Zstd
+        )));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("org.gnome.Builder", "org.gnome.Builder"));
+        assert!(!glob_match("org.gnome.Builder", "org.gnome.Builder2"));
+        assert!(glob_match("org.gnome.*", "org.gnome.Builder"));
+        assert!(glob_match("*.Builder", "org.gnome.Builder"));
+        assert!(glob_match("org.*.Platform", "org.kde.Platform"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("org.kde.*", "org.gnome.Builder"));
+    }
+
     #[test]
     fn test_is_primary_ref() {
-        assert!(is_primary_ref("app/org.gnome.Builder/x86_64/stable"));
-        assert!(!is_primary_ref("runtime/org.gnome.Platform/x86_64/3.38"));
+        let skiplist = vec!["org.mozilla.firefox".to_string()];
+        assert!(is_primary_ref(
+            "app/org.gnome.Builder/x86_64/stable",
+            &skiplist
+        ));
+        assert!(!is_primary_ref(
+            "runtime/org.gnome.Platform/x86_64/3.38",
+            &skiplist
+        ));
+        assert!(!is_primary_ref(
+            "runtime/org.gnome.Builder.Sources/x86_64/stable",
+            &skiplist
+        ));
         assert!(!is_primary_ref(
-            "runtime/org.gnome.Builder.Sources/x86_64/stable"
+            "app/org.mozilla.firefox/x86_64/stable",
+            &skiplist
         ));
     }
 }
@@ -0,0 +1,253 @@
+use std::ops::Range;
+
+use roxmltree::Node;
+
+/// Edits an XML document by replacing byte ranges of the original text, so everything that isn't explicitly changed
+/// (whitespace, comments, the declaration, attribute quoting...) stays byte-for-byte identical.
+///
+/// All positions refer to the original document, so nodes from a `roxmltree::Document` parsed from it can be passed
+/// in directly. Edits must not overlap.
+pub struct XmlEditor<'a> {
+    original: &'a str,
+    edits: Vec<(Range<usize>, String)>,
+}
+
+impl<'a> XmlEditor<'a> {
+    pub fn new(original: &'a str) -> Self {
+        Self {
+            original,
+            edits: vec![],
+        }
+    }
+
+    /// Applies the edits and returns the new document.
+    pub fn finish(mut self) -> String {
+        let mut result = self.original.to_string();
+
+        // Apply from the end of the document so earlier positions stay valid. Insertions at the same position are
+        // applied in reverse, so they end up in the order they were made.
+        let mut edits: Vec<_> = self.edits.drain(..).enumerate().collect();
+        edits.sort_by(|(i, (a, _)), (j, (b, _))| (b.start, j).cmp(&(a.start, i)));
+
+        for (_, (range, replacement)) in edits {
+            result.replace_range(range, &replacement);
+        }
+
+        result
+    }
+
+    /// Removes an element, along with the indentation before it if it's on its own line.
+    pub fn remove_element(&mut self, node: Node) {
+        let range = node.range();
+        let line_start = self.original[..range.start]
+            .rfind('\n')
+            .filter(|i| self.original[*i..range.start].trim().is_empty());
+
+        let start = line_start.unwrap_or(range.start);
+        self.edits.push((start..range.end, String::new()));
+    }
+
+    /// Replaces the text content of an element.
+    pub fn set_text(&mut self, node: Node, text: &str) {
+        match self.content_range(node) {
+            Some(content) => self.edits.push((content, escape(text))),
+            None => {
+                // Expand a self-closing tag
+                let end = self.start_tag_end(node);
+                self.edits.push((
+                    end - 2..end,
+                    format!(">{}</{}>", escape(text), qualified_name(node)),
+                ));
+            }
+        }
+    }
+
+    /// Adds child elements (given as XML strings) at the end of an element, each on its own line and indented to
+    /// match the existing children.
+    pub fn append_children(&mut self, parent: Node, children: &[String]) {
+        let indent = self.child_indent(parent);
+        let last_child = parent.last_element_child();
+
+        if let Some(last_child) = last_child {
+            let insertion: String = children
+                .iter()
+                .map(|child| format!("\n{indent}{child}"))
+                .collect();
+            self.edits
+                .push((last_child.range().end..last_child.range().end, insertion));
+        } else {
+            let parent_indent = self.indent_of(parent);
+            let mut content: String = children
+                .iter()
+                .map(|child| format!("\n{indent}{child}"))
+                .collect();
+            content.push('\n');
+            content.push_str(parent_indent);
+
+            match self.content_range(parent) {
+                Some(content_range) => self.edits.push((content_range, content)),
+                None => {
+                    // Expand a self-closing tag
+                    let range = parent.range();
+                    let start_tag = self.original[range.start..range.end - 2].trim_end();
+                    self.edits.push((
+                        range,
+                        format!("{start_tag}>{content}</{}>", qualified_name(parent)),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Adds a new element at the end of `parent`, containing the given child elements (as XML strings), indented to
+    /// match the rest of the document.
+    pub fn append_element(&mut self, parent: Node, tag: &str, children: &[String]) {
+        let indent = self.child_indent(parent);
+        let child_indent = format!("{indent}{}", self.indent_unit(parent));
+        let content: String = children
+            .iter()
+            .map(|child| format!("\n{child_indent}{child}"))
+            .collect();
+        self.append_children(parent, &[format!("<{tag}>{content}\n{indent}</{tag}>")]);
+    }
+
+    /// The indentation for a new child of the given element.
+    fn child_indent(&self, parent: Node) -> String {
+        match parent.last_element_child() {
+            Some(last_child) => self.indent_of(last_child).to_string(),
+            None => format!("{}{}", self.indent_of(parent), self.indent_unit(parent)),
+        }
+    }
+
+    /// The position just after an element's start tag.
+    fn start_tag_end(&self, node: Node) -> usize {
+        let range = node.range();
+
+        // Skip past the attributes, since their values may contain '>'
+        let after_attrs = node
+            .attributes()
+            .map(|attr| attr.range().end)
+            .max()
+            .unwrap_or(range.start);
+        after_attrs + self.original[after_attrs..range.end].find('>').unwrap() + 1
+    }
+
+    /// The range between an element's start and end tags, or `None` if it's a self-closing tag.
+    fn content_range(&self, node: Node) -> Option<Range<usize>> {
+        let range = node.range();
+        let start = self.start_tag_end(node);
+
+        if start == range.end && self.original[..start].ends_with("/>") {
+            return None;
+        }
+
+        let end = range.start + self.original[range.start..range.end].rfind("</").unwrap();
+        Some(start..end)
+    }
+
+    /// The whitespace at the start of the line the node starts on, or an empty string if there's something else
+    /// before it on that line.
+    fn indent_of(&self, node: Node) -> &'a str {
+        let start = node.range().start;
+        let line_start = self.original[..start].rfind('\n').map_or(0, |i| i + 1);
+        let prefix = &self.original[line_start..start];
+        if prefix.trim().is_empty() {
+            prefix
+        } else {
+            ""
+        }
+    }
+
+    /// The indentation added at each nesting level, guessed from the nearest ancestor that has children.
+    fn indent_unit(&self, node: Node) -> String {
+        for ancestor in node.ancestors().filter(|n| n.is_element()) {
+            if let Some(child) = ancestor.children().find(|c| c.is_element()) {
+                let parent_indent = self.indent_of(ancestor);
+                let child_indent = self.indent_of(child);
+                if let Some(unit) = child_indent.strip_prefix(parent_indent) {
+                    if !unit.is_empty() {
+                        return unit.to_string();
+                    }
+                }
+            }
+        }
+        "  ".to_string()
+    }
+}
+
+fn qualified_name(node: Node) -> String {
+    let name = node.tag_name().name();
+    match node
+        .tag_name()
+        .namespace()
+        .and_then(|ns| node.lookup_prefix(ns))
+    {
+        Some(prefix) if !prefix.is_empty() => format!("{prefix}:{name}"),
+        _ => name.to_string(),
+    }
+}
+
+/// Escapes a string for use as element text or an attribute value.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Builds an element with the given attributes and text, like `<value key="k">v</value>`.
+pub fn element(tag: &str, attrs: &[(&str, &str)], text: &str) -> String {
+    let attrs: String = attrs
+        .iter()
+        .map(|(k, v)| format!(" {k}=\"{}\"", escape(v)))
+        .collect();
+    format!("<{tag}{attrs}>{}</{tag}>", escape(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use roxmltree::Document;
+
+    use super::*;
+
+    #[test]
+    fn test_untouched_document_is_identical() {
+        let original = "<?xml version=\"1.0\"?>\n<!-- comment -->\n<a  x='1'>\n\t<b/>\n</a>\n";
+        let editor = XmlEditor::new(original);
+        assert_eq!(editor.finish(), original);
+    }
+
+    #[test]
+    fn test_edits() {
+        let original = "<a>\n  <!-- keep -->\n  <b>old</b>\n  <c/>\n  <d x=\"&gt;\"></d>\n</a>";
+        let doc = Document::parse(original).unwrap();
+        let root = doc.root_element();
+        let find = |name: &str| root.children().find(|n| n.has_tag_name(name)).unwrap();
+
+        let mut editor = XmlEditor::new(original);
+        editor.set_text(find("b"), "new & improved");
+        editor.remove_element(find("c"));
+        editor.append_children(find("d"), &[element("e", &[], "1")]);
+        editor.append_children(root, &[element("f", &[("k", "v")], "2")]);
+
+        assert_eq!(
+            editor.finish(),
+            "<a>\n  <!-- keep -->\n  <b>new &amp; improved</b>\n  <d x=\"&gt;\">\n    <e>1</e>\n  </d>\n  <f k=\"v\">2</f>\n</a>"
+        );
+    }
+
+    #[test]
+    fn test_expand_self_closing() {
+        let original = "<a>\n    <b />\n</a>";
+        let doc = Document::parse(original).unwrap();
+        let b = doc.root_element().first_element_child().unwrap();
+
+        let mut editor = XmlEditor::new(original);
+        editor.append_children(b, &[element("c", &[], "x")]);
+
+        assert_eq!(
+            editor.finish(),
+            "<a>\n    <b>\n        <c>x</c>\n    </b>\n</a>"
+        );
+    }
+}